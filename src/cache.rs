@@ -0,0 +1,117 @@
+use crate::processor::HashAlgorithm;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+/// The name of the cache file, stored next to the update.xml.
+pub const CACHE_FILE: &str = ".mapcheck-cache.json";
+
+/// The prefix of the extended attribute that holds the verified digest of a file, followed by the hash algorithm.
+const STAMP_PREFIX: &str = "user.mapcheck.";
+
+/// Returns the verified digest that was stored in an extended attribute of the file by [`write_stamp`], if the file
+/// was not modified since it was verified.
+#[cfg(unix)]
+pub fn read_stamp(
+    path: &Path,
+    algorithm: HashAlgorithm,
+    size: u64,
+    mtime: SystemTime,
+) -> Option<String> {
+    let value = xattr::get(path, format!("{STAMP_PREFIX}{algorithm}")).ok()??;
+    let entry: CacheEntry = serde_json::from_slice(&value).ok()?;
+    (entry.size == size && entry.mtime == mtime && entry.algorithm == algorithm)
+        .then(|| entry.digest)
+}
+
+#[cfg(not(unix))]
+pub fn read_stamp(
+    _path: &Path,
+    _algorithm: HashAlgorithm,
+    _size: u64,
+    _mtime: SystemTime,
+) -> Option<String> {
+    None
+}
+
+/// Stores the verified digest in an extended attribute of the file, so it travels with the file.
+#[cfg(unix)]
+pub fn write_stamp(path: &Path, entry: &CacheEntry) -> io::Result<()> {
+    let value = serde_json::to_vec(entry)?;
+    xattr::set(path, format!("{STAMP_PREFIX}{}", entry.algorithm), &value)
+}
+
+#[cfg(not(unix))]
+pub fn write_stamp(_path: &Path, _entry: &CacheEntry) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "extended attributes are only supported on Unix",
+    ))
+}
+
+/// Remembers which files were verified before, so they don't have to be hashed again.
+#[derive(Debug, Default)]
+pub struct Cache {
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub size: u64,
+    pub mtime: SystemTime,
+    pub algorithm: HashAlgorithm,
+    pub digest: String,
+}
+
+impl Cache {
+    /// Loads the cache from `path`, starting with an empty cache if it does not exist or cannot be read.
+    pub fn load(path: &Path) -> Self {
+        let entries = File::open(path)
+            .ok()
+            .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+            .unwrap_or_default();
+        Cache {
+            entries: Mutex::new(entries),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = File::create(path).context("Could not create cache file")?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, &*self.entries.lock().unwrap())
+            .context("Could not write cache file")?;
+        writer.flush().context("Could not write cache file")
+    }
+
+    /// Returns the verified digest of a file, if it was not modified since it was verified.
+    pub fn get(
+        &self,
+        filename: &str,
+        size: u64,
+        mtime: SystemTime,
+        algorithm: HashAlgorithm,
+    ) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(filename)?;
+        if entry.size != size || entry.mtime != mtime {
+            entries.remove(filename);
+            return None;
+        }
+        (entry.algorithm == algorithm).then(|| entry.digest.clone())
+    }
+
+    pub fn insert(&self, filename: String, entry: CacheEntry) {
+        self.entries.lock().unwrap().insert(filename, entry);
+    }
+
+    pub fn remove(&self, filename: &str) {
+        self.entries.lock().unwrap().remove(filename);
+    }
+}
@@ -0,0 +1,66 @@
+use crate::{problem::Problem, processor::HashAlgorithm};
+use anyhow::{Context, Result};
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+/// Appends the result of every checked file to a log file, shared between the worker threads.
+#[derive(Debug)]
+pub struct LogWriter {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl LogWriter {
+    /// Opens the log file for appending and writes a header line.
+    pub fn open(path: &Path, region: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context("Could not open log file")?;
+        let log = LogWriter {
+            writer: Mutex::new(BufWriter::new(file)),
+        };
+        log.write(format_args!(
+            "# {region} checked at {}",
+            humantime::format_rfc3339_seconds(SystemTime::now())
+        ));
+        Ok(log)
+    }
+
+    pub fn ok(&self, filename: &str, checksum: Option<(HashAlgorithm, &str)>) {
+        match checksum {
+            Some((algorithm, digest)) => {
+                self.write(format_args!("ok\t{filename}\t{algorithm}:{digest}"))
+            }
+            None => self.write(format_args!("ok\t{filename}")),
+        }
+    }
+
+    pub fn problem(&self, problem: &Problem) {
+        self.write(format_args!(
+            "{}\t{}\t{}",
+            problem.kind(),
+            problem.filename().unwrap_or("-"),
+            problem.full_message()
+        ));
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.writer
+            .lock()
+            .unwrap()
+            .flush()
+            .context("Could not write log file")
+    }
+
+    fn write(&self, line: fmt::Arguments) {
+        // Errors are reported by `flush`, because the writer keeps failing once it is broken.
+        let _ = writeln!(self.writer.lock().unwrap(), "{line}");
+    }
+}
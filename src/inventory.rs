@@ -0,0 +1,64 @@
+use crate::{
+    manifest::{Country, ZipFile},
+    problem::Problem,
+};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{collections::HashMap, fs::DirEntry, path::Path};
+
+/// A row of the CSV inventory written by `--csv`.
+#[derive(Debug, Serialize)]
+pub struct InventoryRow<'a> {
+    pub country_id: u32,
+    pub country_name: &'a str,
+    pub filename: String,
+    pub expected_size: u64,
+    /// Empty when the file was not found.
+    pub actual_size: Option<u64>,
+    /// Empty when the manifest has no md5 for the file.
+    pub expected_md5: Option<&'a str>,
+    /// `ok` or the kind of problem found with the file.
+    pub status: &'static str,
+}
+
+/// Builds a row for every checked file of `countries`, with the status from `problems`.
+pub fn inventory<'a>(
+    countries: &[&'a Country],
+    files: &[ZipFile<'a>],
+    zip_files: &HashMap<String, DirEntry>,
+    problems: &[Problem],
+) -> Vec<InventoryRow<'a>> {
+    let statuses: HashMap<_, _> = problems
+        .iter()
+        .filter_map(|p| Some((p.filename()?, p.kind())))
+        .collect();
+    countries
+        .iter()
+        .flat_map(|country| {
+            files
+                .iter()
+                .filter(move |f| f.country_id == country.id)
+                .map(move |f| (country, f.clone()))
+        })
+        .map(|(country, file)| InventoryRow {
+            country_id: country.id,
+            country_name: &country.name,
+            actual_size: zip_files
+                .get(&file.filename)
+                .and_then(|e| e.metadata().ok())
+                .map(|m| m.len()),
+            status: statuses.get(&file.filename[..]).copied().unwrap_or("ok"),
+            expected_size: file.packedsize,
+            expected_md5: file.md5,
+            filename: file.filename,
+        })
+        .collect()
+}
+
+pub fn write_csv(path: &Path, rows: &[InventoryRow]) -> Result<()> {
+    let mut writer = csv::Writer::from_path(path).context("Could not create CSV file")?;
+    for row in rows {
+        writer.serialize(row).context("Could not write CSV file")?;
+    }
+    writer.flush().context("Could not write CSV file")
+}
@@ -0,0 +1,513 @@
+//! Checks downloaded HereV1 maps for corrupt parts.
+//!
+//! Use [`check`] to verify a complete map folder, or the building blocks in this crate to report on the individual
+//! steps.
+
+use crate::{
+    manifest::{Country, GroupFilter, Manifest, ZipFile},
+    problem::Problem,
+    processor::{size_problem, Processor, VerifiedFile},
+    progress::{NoProgress, Progress},
+};
+use anyhow::{Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{info, warn};
+use rayon::{iter::Either, prelude::*, ThreadPoolBuilder};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fs::{read_dir, read_to_string, DirEntry},
+    path::Path,
+};
+
+pub mod cache;
+pub mod check_log;
+pub mod inventory;
+pub mod manifest;
+pub mod md5sums;
+pub mod problem;
+pub mod processor;
+pub mod progress;
+pub mod report;
+pub mod status;
+pub mod summary;
+
+/// Options for [`check`].
+pub struct CheckOptions {
+    pub processor: Processor,
+    /// Only check the files of these countries, all countries are checked when empty.
+    pub countries: Vec<u32>,
+    /// The data groups that are expected to be downloaded.
+    pub groups: GroupFilter,
+    /// Fail when an entry of the directory cannot be read, instead of skipping it.
+    pub strict: bool,
+    pub progress: Box<dyn Progress + Send>,
+}
+
+impl Default for CheckOptions {
+    fn default() -> Self {
+        CheckOptions {
+            processor: Processor::default(),
+            countries: Vec::new(),
+            groups: GroupFilter::default(),
+            strict: false,
+            progress: Box::new(NoProgress),
+        }
+    }
+}
+
+/// The outcome of checking the maps.
+#[derive(Debug, Default)]
+pub struct CheckResult {
+    pub ok: Vec<VerifiedFile>,
+    pub problems: Vec<Problem>,
+}
+
+/// Checks the maps in `dir` against the `update.xml` in that directory.
+pub fn check(dir: &Path, options: CheckOptions) -> Result<CheckResult> {
+    let manifest = Manifest::open(&Manifest::find(dir))?;
+    let (countries, _) = manifest.countries();
+    let expected_names = expected_filenames(&countries, &options.groups);
+    let (mut files, mut problems) = expected_files(countries, &options.groups);
+    select_countries(&mut files, &options.countries);
+    let zip_files = find_zip_files(dir, &NoProgress, options.strict)?;
+    let parts = find_zip_parts(dir)?;
+    let (files, split) = split_files(files, &zip_files, &parts);
+    let (mut files, size_problems) = check_sizes(files, &zip_files, &options.processor);
+    problems.extend(size_problems);
+    files.extend(split);
+    let analyzed = analyze(
+        files,
+        &zip_files,
+        &parts,
+        &options.processor,
+        &*options.progress,
+    )?;
+    problems.extend(analyzed.problems);
+    problems.extend(find_unexpected_files(&zip_files, &expected_names));
+    Ok(CheckResult {
+        ok: analyzed.ok,
+        problems,
+    })
+}
+
+/// Collects the expected files of all countries, and a problem for every file with invalid info in the manifest.
+/// Files that occur more than once in the manifest are only checked once, with a warning.
+pub fn expected_files<'a>(
+    countries: Vec<&'a Country>,
+    filter: &GroupFilter,
+) -> (Vec<ZipFile<'a>>, Vec<Problem>) {
+    let mut files = Vec::new();
+    let mut problems = Vec::new();
+    let mut seen = HashSet::new();
+    let mut duplicates = Vec::new();
+    for file in countries.into_iter().flat_map(|c| c.files(filter)) {
+        match file {
+            Ok(file) if !seen.insert(file.filename.clone()) => duplicates.push(file.filename),
+            Ok(file) => files.push(file),
+            Err(e) => problems.push(e.downcast().unwrap_or_else(Problem::Error)),
+        }
+    }
+    if !duplicates.is_empty() {
+        warn!(
+            "The manifest lists these files more than once, only the first entry is checked: {}",
+            duplicates.join(", ")
+        );
+    }
+    (files, problems)
+}
+
+/// Returns the names of all files in the manifest, including skipped data groups, because those are not unexpected.
+pub fn expected_filenames(countries: &[&Country], groups: &GroupFilter) -> HashSet<String> {
+    let all = GroupFilter {
+        pattern: groups.pattern.clone(),
+        ..GroupFilter::default()
+    };
+    countries.iter().flat_map(|c| c.filenames(&all)).collect()
+}
+
+/// Only keeps the files of the given countries (if any), returns the ids that did not match any file.
+pub fn select_countries(files: &mut Vec<ZipFile>, ids: &[u32]) -> Vec<u32> {
+    if ids.is_empty() {
+        return Vec::new();
+    }
+    let unknown = ids
+        .iter()
+        .copied()
+        .filter(|id| !files.iter().any(|f| f.country_id == *id))
+        .collect();
+    files.retain(|f| ids.contains(&f.country_id));
+    unknown
+}
+
+/// Only keeps the files matching any of the `include` globs (if any) and none of the `exclude` globs, so excludes win
+/// over includes.
+pub fn select_files(
+    files: &mut Vec<ZipFile>,
+    include: &[String],
+    exclude: &[String],
+) -> Result<()> {
+    let include = glob_set(include)?;
+    let exclude = glob_set(exclude)?;
+    files.retain(|f| {
+        (include.is_empty() || include.is_match(&f.filename)) && !exclude.is_match(&f.filename)
+    });
+    Ok(())
+}
+
+fn glob_set(globs: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob).with_context(|| format!("Invalid glob: {glob}"))?);
+    }
+    builder.build().context("Invalid globs")
+}
+
+/// The name of the file in the maps directory with the globs of the files to ignore.
+pub const IGNORE_FILE: &str = ".mapcheckignore";
+
+/// Reads the globs of the files to ignore from `path`, one per line like a `.gitignore`. Empty lines and lines
+/// starting with `#` are skipped.
+pub fn read_ignore_file(path: &Path) -> Result<GlobSet> {
+    let contents = read_to_string(path)
+        .with_context(|| format!("Could not read ignore file {}", path.display()))?;
+    let globs: Vec<_> = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+    glob_set(&globs).with_context(|| format!("Invalid ignore file {}", path.display()))
+}
+
+/// Finds the zip files in `path`, reporting every directory entry that was seen to `progress`. Directories with a
+/// `.zip` extension are skipped with a warning, as are entries that cannot be read unless `strict` is set.
+pub fn find_zip_files(
+    path: &Path,
+    progress: &dyn Progress,
+    strict: bool,
+) -> Result<HashMap<String, DirEntry>> {
+    read_dir(path)
+        .context("Could not read directory entries")?
+        .inspect(|_| progress.inc(1))
+        .filter_map(|f| match f {
+            Err(e) if strict => Some(Err(e.into())),
+            Err(e) => {
+                warn!("Skipping a directory entry that could not be read: {e}");
+                None
+            }
+            Ok(e) if e.path().extension()?.eq_ignore_ascii_case("zip") => {
+                let filename = e.path().file_name()?.to_string_lossy().into_owned();
+                if e.file_type().map_or(false, |t| t.is_dir()) {
+                    warn!("Skipping {filename}, which is a directory instead of a file");
+                    return None;
+                }
+                Some(Ok((filename, e)))
+            }
+            _ => None,
+        })
+        .collect::<Result<HashMap<String, DirEntry>>>()
+        .context("Error while reading directory entries")
+}
+
+/// Finds the parts of the zip files in `path` that were split into `{name}.zip.001`, `{name}.zip.002`, etc. Returns
+/// the parts by the name of the zip file, ordered by their number.
+pub fn find_zip_parts(path: &Path) -> Result<HashMap<String, Vec<(u32, DirEntry)>>> {
+    let mut parts: HashMap<_, Vec<_>> = HashMap::new();
+    // Unreadable entries are already reported by `find_zip_files`.
+    for entry in read_dir(path)
+        .context("Could not read directory entries")?
+        .filter_map(|e| e.ok())
+    {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let part = match name.rsplit_once('.') {
+            Some((base, number))
+                if base.to_ascii_lowercase().ends_with(".zip")
+                    && !number.is_empty()
+                    && number.bytes().all(|b| b.is_ascii_digit()) =>
+            {
+                number.parse().ok().map(|number| (base.to_string(), number))
+            }
+            _ => None,
+        };
+        if let Some((base, number)) = part {
+            parts.entry(base).or_default().push((number, entry));
+        }
+    }
+    for entries in parts.values_mut() {
+        entries.sort_by_key(|(number, _)| *number);
+    }
+    Ok(parts)
+}
+
+/// Separates the files that only exist as parts (see [`find_zip_parts`]) from the other files, which are returned
+/// first.
+pub fn split_files<'a>(
+    files: Vec<ZipFile<'a>>,
+    zip_files: &HashMap<String, DirEntry>,
+    parts: &HashMap<String, Vec<(u32, DirEntry)>>,
+) -> (Vec<ZipFile<'a>>, Vec<ZipFile<'a>>) {
+    files
+        .into_iter()
+        .partition(|f| zip_files.contains_key(&f.filename) || !parts.contains_key(&f.filename))
+}
+
+/// Returns the combined size of the expected files that exist on disk, either whole or as parts.
+pub fn size_on_disk(
+    files: &[ZipFile],
+    zip_files: &HashMap<String, DirEntry>,
+    parts: &HashMap<String, Vec<(u32, DirEntry)>>,
+) -> u64 {
+    files
+        .iter()
+        .flat_map(|f| match zip_files.get(&f.filename) {
+            Some(entry) => vec![entry],
+            None => parts.get(&f.filename).map_or_else(Vec::new, |parts| {
+                parts.iter().map(|(_, entry)| entry).collect()
+            }),
+        })
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|m| m.len())
+        .sum()
+}
+
+/// Renames the entries of files that only match an expected file when ignoring case, for case-insensitive file
+/// systems. Returns the on-disk and expected name of every renamed entry.
+pub fn match_ignoring_case(
+    zip_files: &mut HashMap<String, DirEntry>,
+    expected_names: &HashSet<String>,
+) -> Vec<(String, String)> {
+    let lowercase: HashMap<_, _> = expected_names
+        .iter()
+        .map(|name| (name.to_lowercase(), name))
+        .collect();
+    let mut renamed: Vec<_> = zip_files
+        .keys()
+        .filter(|name| !expected_names.contains(*name))
+        .filter_map(|name| {
+            let expected = *lowercase.get(&name.to_lowercase())?;
+            (!zip_files.contains_key(expected)).then(|| (name.clone(), expected.clone()))
+        })
+        .collect();
+    renamed.sort();
+    for (name, expected) in &renamed {
+        if let Some(entry) = zip_files.remove(name) {
+            zip_files.insert(expected.clone(), entry);
+        }
+    }
+    renamed
+}
+
+/// Reports missing files and files with the wrong size (unless the processor ignores sizes), without reading them.
+/// Files that the processor judges by their age are left to it, so a file that is still being downloaded is not
+/// reported as truncated. Returns the files that still have to be hashed.
+pub fn check_sizes<'a>(
+    files: Vec<ZipFile<'a>>,
+    zip_files: &HashMap<String, DirEntry>,
+    processor: &Processor,
+) -> (Vec<ZipFile<'a>>, Vec<Problem>) {
+    let mut remaining = Vec::new();
+    let mut problems = Vec::new();
+    for file in files {
+        let problem = match zip_files.get(&file.filename) {
+            None if file.optional => {
+                info!("Optional file {} is not present", file.filename);
+                continue;
+            }
+            None => Some(Problem::NotFound {
+                filename: file.filename.clone(),
+            }),
+            Some(_) if processor.ignore_size => None,
+            // Errors reading the metadata are reported when the file is processed.
+            Some(entry) => entry
+                .metadata()
+                .ok()
+                .filter(|m| {
+                    !m.modified()
+                        .map_or(false, |mtime| processor.judged_by_age(mtime))
+                })
+                .and_then(|m| size_problem(&file, m.len())),
+        };
+        match problem {
+            Some(problem) => problems.push(problem),
+            None => remaining.push(file),
+        }
+    }
+    (remaining, problems)
+}
+
+/// Checks the files in parallel, the largest files first. Files that only exist as `parts` are checked by hashing the
+/// parts in order.
+pub fn analyze(
+    mut files: Vec<ZipFile>,
+    zip_files: &HashMap<String, DirEntry>,
+    parts: &HashMap<String, Vec<(u32, DirEntry)>>,
+    processor: &Processor,
+    progress: &dyn Progress,
+) -> Result<CheckResult> {
+    // Starting the largest files first keeps a large file at the end of the manifest from leaving a single thread
+    // busy while the others are done. This matters most for regions with a few very large countries, compare the
+    // elapsed time of a run with `--no-cache` on such a region when changing the order.
+    files.sort_by_key(|f| Reverse(f.packedsize));
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(processor.threads)
+        .build()
+        .context("Could not start worker threads")?;
+    let check = |expected_file: ZipFile| {
+        let result = match (
+            zip_files.get(&expected_file.filename),
+            parts.get(&expected_file.filename),
+        ) {
+            (Some(actual_file), _) => processor.process_file(progress, actual_file, expected_file),
+            (None, Some(parts)) => processor.process_parts(progress, parts, expected_file),
+            (None, None) => Err(Problem::NotFound {
+                filename: expected_file.filename,
+            }),
+        };
+        progress.file_done();
+        result
+    };
+    Ok(pool.install(|| {
+        if processor.fail_fast {
+            // Files that are already being checked are finished, but no new files are started.
+            CheckResult {
+                ok: Vec::new(),
+                problems: files
+                    .into_par_iter()
+                    .filter(|_| !processor.cancelled())
+                    .find_map_any(|f| check(f).err())
+                    .into_iter()
+                    .collect(),
+            }
+        } else {
+            let (ok, problems) = files
+                .into_par_iter()
+                .filter(|_| !processor.cancelled())
+                .map(check)
+                .partition_map(|r| match r {
+                    Ok(file) => Either::Left(file),
+                    Err(problem) => Either::Right(problem),
+                });
+            CheckResult { ok, problems }
+        }
+    }))
+}
+
+pub fn find_unexpected_files(
+    zip_files: &HashMap<String, DirEntry>,
+    expected_files: &HashSet<String>,
+) -> Vec<Problem> {
+    let mut unexpected: Vec<_> = zip_files
+        .iter()
+        .filter(|(f, _)| !expected_files.contains(*f))
+        .collect();
+    unexpected.sort_by_key(|(filename, _)| *filename);
+    unexpected
+        .into_iter()
+        .map(|(filename, entry)| Problem::Unexpected {
+            filename: filename.clone(),
+            size: entry.metadata().map_or(0, |m| m.len()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs::{create_dir, write, File},
+        time::Duration,
+    };
+
+    #[test]
+    fn find_zip_files_skips_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        create_dir(dir.path().join("foo.zip")).unwrap();
+        File::create(dir.path().join("1_01.zip")).unwrap();
+        let zip_files = find_zip_files(dir.path(), &NoProgress, false).unwrap();
+        let mut names: Vec<_> = zip_files.keys().collect();
+        names.sort();
+        assert_eq!(names, ["1_01.zip"]);
+    }
+
+    #[test]
+    fn find_zip_parts_groups_numbered_parts() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "1_01.zip.002",
+            "1_01.zip.001",
+            "1_01.zip",
+            "1_02.zip.part",
+            "notes.txt.001",
+        ] {
+            File::create(dir.path().join(name)).unwrap();
+        }
+        let parts = find_zip_parts(dir.path()).unwrap();
+        assert_eq!(parts.len(), 1);
+        let numbers: Vec<_> = parts["1_01.zip"].iter().map(|(n, _)| *n).collect();
+        assert_eq!(numbers, [1, 2]);
+    }
+
+    #[test]
+    fn duplicate_files_are_checked_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.xml");
+        write(
+            &path,
+            r#"<update><drmEntry>
+<mapCatalog><region><region id="1" name="Germany">
+<dataGroup id="1" unpackedsize="10" packedsize="124" md5="a0e16f62528decfacbeeca04e225fe45"/>
+<dataGroup id="1" unpackedsize="12" packedsize="126" md5="da1738b759eab0cce1fec25d1525ac0f"/>
+<dataGroup id="2" unpackedsize="12" packedsize="126" md5="da1738b759eab0cce1fec25d1525ac0f"/>
+</region></region></mapCatalog>
+<salesRegion name="Europe"><region id="1"/></salesRegion>
+</drmEntry></update>"#,
+        )
+        .unwrap();
+        let manifest = Manifest::open(&path).unwrap();
+        let (countries, _) = manifest.countries();
+        let (files, problems) = expected_files(countries, &GroupFilter::default());
+        let names: Vec<_> = files.iter().map(|f| &f.filename[..]).collect();
+        assert_eq!(names, ["1_01.zip", "1_02.zip"]);
+        // The first entry wins.
+        assert_eq!(files[0].packedsize, 124);
+        assert!(problems.is_empty());
+    }
+
+    #[test]
+    fn recent_files_are_not_size_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("update.xml");
+        write(
+            &path,
+            r#"<update><drmEntry>
+<mapCatalog><region><region id="1" name="Germany">
+<dataGroup id="1" unpackedsize="10" packedsize="124" md5="a0e16f62528decfacbeeca04e225fe45"/>
+</region></region></mapCatalog>
+<salesRegion name="Europe"><region id="1"/></salesRegion>
+</drmEntry></update>"#,
+        )
+        .unwrap();
+        // Larger than expected, and just modified.
+        write(dir.path().join("1_01.zip"), [0; 200]).unwrap();
+        let manifest = Manifest::open(&path).unwrap();
+        let zip_files = find_zip_files(dir.path(), &NoProgress, false).unwrap();
+        let check = |processor: &Processor| {
+            let (countries, _) = manifest.countries();
+            let (files, _) = expected_files(countries, &GroupFilter::default());
+            let (remaining, problems) = check_sizes(files, &zip_files, processor);
+            (remaining.len(), problems)
+        };
+
+        let (remaining, problems) = check(&Processor {
+            min_age: Duration::from_secs(3600),
+            ..Processor::default()
+        });
+        assert_eq!(remaining, 1);
+        assert!(problems.is_empty());
+
+        let (remaining, problems) = check(&Processor::default());
+        assert_eq!(remaining, 0);
+        assert!(matches!(problems[..], [Problem::WrongSize { .. }]));
+    }
+}
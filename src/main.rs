@@ -1,164 +1,1718 @@
-use crate::{
-    manifest::{Manifest, ZipFile},
-    problem::{Problem, ProblemList},
-};
-use anyhow::{anyhow, Context, Result};
+use anyhow::{bail, Context, Result};
 use console::Style;
-use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
-use processor::process_file;
-use rayon::prelude::*;
+use env_logger::{Builder, Env};
+use indicatif::{HumanBytes, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{info, warn, Level};
+use maps_download_check::{
+    analyze,
+    cache::{Cache, CACHE_FILE},
+    check_log::LogWriter,
+    check_sizes, expected_filenames, expected_files, find_unexpected_files, find_zip_files,
+    find_zip_parts,
+    inventory::{inventory, write_csv},
+    manifest::{FilenamePattern, GroupFilter, Manifest, ZipFile},
+    match_ignoring_case, md5sums,
+    problem::{missing_files_msg, Problem, ProblemList},
+    processor::{ByteBudget, Dedup, HashAlgorithm, Hashers, Processor, Timings},
+    progress::{FileCountProgress, MachineProgress, NoProgress},
+    read_ignore_file,
+    report::Report,
+    select_countries, select_files, size_on_disk, split_files,
+    status::{country_statuses, incomplete, CountryStatus},
+    summary::Summary,
+    CheckResult, IGNORE_FILE,
+};
+use notify::{EventKind, RecursiveMode, Watcher};
+#[cfg(feature = "gui")]
 use rfd::FileDialog;
 use std::{
-    collections::HashMap,
-    fs::{read_dir, remove_file, DirEntry},
-    io::{stdin, stdout, Write},
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap, HashSet},
+    env,
+    fs::{copy, create_dir_all, read_dir, remove_dir, remove_file, rename, write, DirEntry},
+    io::{stderr, stdin, BufRead, Write},
     path::{Path, PathBuf},
+    process::exit,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, RecvTimeoutError},
+        Arc,
+    },
+    thread::sleep,
+    time::{Duration, Instant},
 };
 use structopt::StructOpt;
 
-mod manifest;
-mod problem;
-mod processor;
-
 /// Checks downloaded HereV1 maps and (optionally) deletes files that are corrupt so they can be downloaded again by the downloader.
-#[derive(Debug, StructOpt)]
+#[derive(Debug, Clone, StructOpt)]
 pub struct Opt {
-    /// The directory where the downloaded maps are stored. Presents a folder-picker if not provided.
-    pub dir: Option<PathBuf>,
+    /// The directories where the downloaded maps are stored, each with its own update.xml. Presents a folder-picker if
+    /// not provided (and a display is available).
+    #[structopt(name = "DIR", parse(from_os_str))]
+    pub dirs: Vec<PathBuf>,
+
+    /// The manifest to check against, defaults to the update.xml in the directory with the maps. May also be a
+    /// http(s) URL when built with the `remote` feature.
+    #[structopt(long, parse(from_os_str))]
+    pub manifest: Option<PathBuf>,
+
+    /// Stop when the manifest is for another region (ignoring case), to avoid checking the maps against the wrong
+    /// manifest.
+    #[structopt(long)]
+    pub expect_region: Option<String>,
+
+    /// Fail when the checksum of the map catalog in the manifest does not match, instead of only warning.
+    #[structopt(long)]
+    pub strict_manifest: bool,
 
     /// Delete corrupt files without confirmation.
     #[structopt(short, long)]
     pub force_delete: bool,
+
+    /// Ask for confirmation before removing each corrupt file.
+    #[structopt(short, long, conflicts_with_all = &["force-delete", "dry-run"])]
+    pub interactive: bool,
+
+    /// Only print which corrupt files would be removed, without removing them.
+    #[structopt(long, conflicts_with = "force-delete")]
+    pub dry_run: bool,
+
+    /// Only report the state of the maps, without asking anything or removing any files.
+    #[structopt(
+        long,
+        conflicts_with_all = &["force-delete", "interactive", "dry-run", "quarantine", "fix-case"]
+    )]
+    pub check_only: bool,
+
+    /// Move corrupt files into this directory instead of deleting them.
+    #[structopt(long, parse(from_os_str))]
+    pub quarantine: Option<PathBuf>,
+
+    /// Also remove zip files that are not part of the manifest.
+    #[structopt(long)]
+    pub delete_unexpected: bool,
+
+    /// Remove the directories below the maps directory that became empty by removing or moving the corrupt files.
+    #[structopt(long)]
+    pub prune_empty_dirs: bool,
+
+    /// The hash algorithm used to verify the files. Falls back to the strongest checksum in the manifest for files
+    /// without one of this algorithm, which is also the default.
+    #[structopt(long, possible_values = HashAlgorithm::VARIANTS)]
+    pub hash: Option<HashAlgorithm>,
+
+    /// Only compare the file sizes with the manifest, without hashing the files. Much faster, but will not detect all
+    /// corrupt files.
+    #[structopt(long, conflicts_with = "deep")]
+    pub fast: bool,
+
+    /// Do not compare file sizes with the manifest and only rely on checksums, for mirrors that pad files.
+    #[structopt(long, conflicts_with = "fast")]
+    pub ignore_size: bool,
+
+    /// Also verify the unpacked size of every archive (slower).
+    #[structopt(long)]
+    pub deep: bool,
+
+    /// Only check the files matching this glob, e.g. `*_speech_recognition.zip` (can be repeated).
+    #[structopt(long, number_of_values = 1)]
+    pub include: Vec<String>,
+
+    /// Do not check the files matching this glob (can be repeated), takes precedence over `--include`.
+    #[structopt(long, number_of_values = 1)]
+    pub exclude: Vec<String>,
+
+    /// Ignore the files matching the globs in this file completely, they are neither checked nor reported. Defaults to
+    /// `.mapcheckignore` in the maps directory, if it exists.
+    #[structopt(long, parse(from_os_str))]
+    pub ignore_file: Option<PathBuf>,
+
+    /// Do not expect the files of the data group with this id, because it was deliberately not downloaded (can be
+    /// repeated).
+    #[structopt(long = "skip-group", number_of_values = 1)]
+    pub skip_groups: Vec<u32>,
+
+    /// Do not expect the speech recognition files, because they were deliberately not downloaded.
+    #[structopt(long)]
+    pub no_speech: bool,
+
+    /// How the files are named, for mirrors that rename them. `{id}` is replaced by the id of the country, `{dg}` by
+    /// the id of the data group and `{dg02}` by the id padded to two digits (or `speech_recognition` for the speech
+    /// recognition package).
+    #[structopt(long, default_value = FilenamePattern::DEFAULT)]
+    pub filename_pattern: FilenamePattern,
+
+    /// Match filenames case-insensitively, always enabled on Windows and macOS.
+    #[structopt(long)]
+    pub ignore_case: bool,
+
+    /// Rename files that only match the manifest when ignoring case to the name in the manifest, instead of reporting
+    /// them.
+    #[structopt(long)]
+    pub fix_case: bool,
+
+    /// Cross-check the md5 checksums in the manifest with this checksums file (in the format of `md5sum`).
+    #[structopt(long, parse(from_os_str))]
+    pub md5_from_file: Option<PathBuf>,
+
+    /// Only print the name, size and checksum (see --hash) of every expected file (separated by tabs), without looking
+    /// at the files.
+    #[structopt(long, conflicts_with_all = &["file", "watch"])]
+    pub list: bool,
+
+    /// Only check this file (e.g. `2010405_01.zip`) and print the result, without scanning the whole folder.
+    #[structopt(long, conflicts_with = "watch")]
+    pub file: Option<String>,
+
+    /// Fail before checking any files when the unpacked maps would take more than this many bytes.
+    #[structopt(long)]
+    pub max_unpacked: Option<u64>,
+
+    /// Do not treat a checksum mismatch of this file as a problem, e.g. for a file that is known to be repacked (can be
+    /// repeated). Size problems are still reported.
+    #[structopt(long, number_of_values = 1)]
+    pub allow_signature: Vec<String>,
+
+    /// Fail when an entry of the directory cannot be read, instead of skipping it with a warning.
+    #[structopt(long)]
+    pub strict: bool,
+
+    /// Only check the files of the country with this id (can be repeated).
+    #[structopt(long = "country", number_of_values = 1)]
+    pub countries: Vec<u32>,
+
+    /// The number of files to check in parallel, defaults to one per CPU core. On HDD-backed storage `--threads 1` is
+    /// often fastest, because parallel reads make the disk seek back and forth.
+    #[structopt(long, default_value = "0")]
+    pub threads: usize,
+
+    /// The number of files to read in parallel when hashing on separate threads (see --threads-hash), replaces
+    /// --threads.
+    #[structopt(long, conflicts_with = "threads")]
+    pub threads_io: Option<usize>,
+
+    /// Hash the files on this many separate threads, while other threads read them. Useful when reading benefits from
+    /// more parallelism than hashing, e.g. on network storage.
+    #[structopt(long)]
+    pub threads_hash: Option<usize>,
+
+    /// Skip files that were modified less than this many seconds ago, because the downloader is probably still writing
+    /// them.
+    #[structopt(long, default_value = "30")]
+    pub min_age: u64,
+
+    /// Only check files that were modified less than this long ago (e.g. "1h" or "30m"), assuming older files are
+    /// ok. Speeds up checking after an incremental download.
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    pub newer_than: Option<Duration>,
+
+    /// Give up on a file when reading it stalls for this many seconds, e.g. on a stale network mount.
+    #[structopt(long)]
+    pub file_timeout: Option<u64>,
+
+    /// The size of the read buffer used for hashing in KiB. Larger buffers can be faster on SSDs.
+    #[structopt(long, default_value = "128")]
+    pub buffer_size: usize,
+
+    /// Limit the total size of the files that are checked at the same time to this many bytes, to bound memory use on
+    /// small devices. A larger file is checked on its own.
+    #[structopt(long)]
+    pub max_concurrent_bytes: Option<u64>,
+
+    /// The number of times to retry reading a file after a transient I/O error, e.g. on network-mounted storage.
+    #[structopt(long, default_value = "2")]
+    pub retries: u32,
+
+    /// Append the result of every checked file to this log file.
+    #[structopt(long, parse(from_os_str))]
+    pub log: Option<PathBuf>,
+
+    /// Write a JSON report of all problems to this file.
+    #[structopt(long, parse(from_os_str))]
+    pub report: Option<PathBuf>,
+
+    /// Write a CSV file with the status of every expected file to this file.
+    #[structopt(long, parse(from_os_str))]
+    pub csv: Option<PathBuf>,
+
+    /// Write the names of all missing files to this file, one per line, e.g. to pass them to a downloader.
+    #[structopt(long, parse(from_os_str))]
+    pub missing_out: Option<PathBuf>,
+
+    /// Print the files that took the longest to check, to find out what dominates the runtime.
+    #[structopt(long)]
+    pub timings: bool,
+
+    /// Hash files that are hard links to the same data only once (only supported on Unix).
+    #[structopt(long)]
+    pub dedup: bool,
+
+    /// Stop at the first problem, without checking the remaining files or removing anything.
+    #[structopt(long)]
+    pub fail_fast: bool,
+
+    /// Only print the names of the corrupt, incomplete and missing files to stdout, one per line, to pass them to the
+    /// downloader. Nothing is removed, other output goes to stderr.
+    #[structopt(long, conflicts_with_all = &["verbose", "fail-fast"])]
+    pub emit_redownload: bool,
+
+    /// How to print the problems: `text` for humans, `github` prints GitHub Actions annotations to stdout, without
+    /// removing anything.
+    #[structopt(long, default_value = "text", possible_values = OutputFormat::VARIANTS)]
+    pub format: OutputFormat,
+
+    /// The order in which problems are listed: by filename, by size (largest first) or by type.
+    #[structopt(long, default_value = "name", possible_values = SortOrder::VARIANTS)]
+    pub sort: SortOrder,
+
+    /// Keep running after the check, and check the expected files again whenever they are created or modified.
+    #[structopt(long)]
+    pub watch: bool,
+
+    /// Check again every this many seconds until there are no problems, e.g. while the downloader is still running.
+    /// Nothing is removed.
+    #[structopt(long, conflicts_with_all = &["watch", "file", "force-delete", "interactive"])]
+    pub wait_until_complete: Option<u64>,
+
+    /// Give up waiting for the maps to be complete after this many checks.
+    #[structopt(long, requires = "wait-until-complete")]
+    pub max_attempts: Option<u32>,
+
+    /// Print every verified file with its checksum, repeat (-vv) to also print the time it took.
+    #[structopt(short, long, parse(from_occurrences), conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Only print a one-line summary, without a progress bar. Warnings and errors are still printed to stderr.
+    #[structopt(short, long)]
+    pub quiet: bool,
+
+    /// Do not show a progress bar.
+    #[structopt(long)]
+    pub no_progress: bool,
+
+    /// How to show the progress of the check, `machine` prints `PROGRESS <bytes done> <bytes total>` lines to stderr
+    /// for a parent process (also when --quiet).
+    #[structopt(long, default_value = "bar", possible_values = ProgressFormat::VARIANTS)]
+    pub progress_format: ProgressFormat,
+
+    /// Set by the Ctrl-C handler.
+    #[structopt(skip)]
+    pub interrupted: Arc<AtomicBool>,
+
+    /// Ignore previously verified files and check every file again.
+    #[structopt(long)]
+    pub no_cache: bool,
+
+    /// Also remember verified files in an extended attribute of each file, which is kept when the files are copied
+    /// with their attributes (only supported on Unix).
+    #[structopt(long)]
+    pub xattr_cache: bool,
+
+    /// When to use colors in the output. `auto` disables colors when the output is not a terminal or when the
+    /// NO_COLOR environment variable is set.
+    #[structopt(long, default_value = "auto", possible_values = ColorChoice::VARIANTS)]
+    pub color: ColorChoice,
+}
+
+impl Opt {
+    /// Whether this run only reports the state of the maps, so it must not write anything to the maps folder.
+    fn read_only(&self) -> bool {
+        self.check_only
+            || self.dry_run
+            || self.fail_fast
+            || self.emit_redownload
+            || self.format == OutputFormat::Github
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub const VARIANTS: &'static [&'static str] = &["auto", "always", "never"];
+
+    fn apply(self) {
+        let enabled = match self {
+            Self::Auto if env::var_os("NO_COLOR").map_or(false, |v| !v.is_empty()) => false,
+            Self::Auto => return,
+            Self::Always => true,
+            Self::Never => false,
+        };
+        console::set_colors_enabled(enabled);
+        console::set_colors_enabled_stderr(enabled);
+    }
+}
+
+impl FromStr for ColorChoice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => bail!("unknown color choice: {s}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressFormat {
+    Bar,
+    Machine,
+}
+
+impl ProgressFormat {
+    pub const VARIANTS: &'static [&'static str] = &["bar", "machine"];
+}
+
+impl FromStr for ProgressFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bar" => Ok(Self::Bar),
+            "machine" => Ok(Self::Machine),
+            _ => bail!("unknown progress format: {s}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Github,
+}
+
+impl OutputFormat {
+    pub const VARIANTS: &'static [&'static str] = &["text", "github"];
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Self::Text),
+            "github" => Ok(Self::Github),
+            _ => bail!("unknown output format: {s}"),
+        }
+    }
+}
+
+/// How often `--progress-format machine` prints the progress.
+const MACHINE_PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+const SCAN_TEMPLATE: &str = "{spinner} Scanning directory... {pos} entries";
+
+const PROGRESS_TEMPLATE: &str =
+    "[{elapsed_precise}] {bar:40} {bytes:.bold}/{total_bytes:.bold} ({bytes_per_sec}, {eta_precise} remaining) {msg}";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Name,
+    Size,
+    Type,
+}
+
+impl SortOrder {
+    pub const VARIANTS: &'static [&'static str] = &["name", "size", "type"];
+
+    fn sort(self, problems: &mut [Problem]) {
+        match self {
+            Self::Name => problems.sort_by(|a, b| a.filename().cmp(&b.filename())),
+            Self::Size => problems.sort_by(|a, b| {
+                b.size()
+                    .cmp(&a.size())
+                    .then_with(|| a.filename().cmp(&b.filename()))
+            }),
+            Self::Type => problems.sort_by(|a, b| {
+                a.kind()
+                    .cmp(b.kind())
+                    .then_with(|| a.filename().cmp(&b.filename()))
+            }),
+        }
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "name" => Ok(Self::Name),
+            "size" => Ok(Self::Size),
+            "type" => Ok(Self::Type),
+            _ => bail!("unknown sort order: {s}"),
+        }
+    }
+}
+
+/// The exit code of the process. Note that removing corrupt files still results in [`Status::Problems`], because the
+/// downloader has to run again before the maps are complete.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Status {
+    Clean = 0,
+    Problems = 1,
+    Error = 2,
+    /// Stopped by Ctrl-C, like the exit code of a shell.
+    Interrupted = 130,
+}
+
+impl Status {
+    fn describe(self) -> &'static str {
+        match self {
+            Status::Clean => "no problems",
+            Status::Problems => "problems found",
+            Status::Error => "could not be checked",
+            Status::Interrupted => "interrupted",
+        }
+    }
 }
 
-fn main() -> Result<()> {
+fn main() {
+    let mut opt = match Opt::from_args_safe() {
+        Ok(opt) => opt,
+        Err(e) if e.use_stderr() => {
+            eprintln!("{}", e.message);
+            exit(Status::Error as i32)
+        }
+        Err(e) => e.exit(),
+    };
+    // Keep stdout clean for the list of files.
+    opt.quiet |= opt.emit_redownload;
+    init_logger(opt.quiet);
+    let interrupted = opt.interrupted.clone();
+    let handler = ctrlc::set_handler(move || {
+        if interrupted.swap(true, Ordering::SeqCst) {
+            exit(Status::Interrupted as i32);
+        }
+        eprintln!("\nStopping, press Ctrl-C again to stop immediately");
+    });
+    if let Err(e) = handler {
+        warn!("Could not install Ctrl-C handler: {e}");
+    }
+    let status = run(opt).unwrap_or_else(|e| {
+        eprintln!("Error: {e:?}");
+        Status::Error
+    });
+    exit(status as i32)
+}
+
+fn run(opt: Opt) -> Result<Status> {
+    opt.color.apply();
+    if let Some(manifest) = &opt.manifest {
+        if !is_url(manifest) && !manifest.is_file() {
+            bail!("Manifest not found: {}", manifest.display());
+        }
+    }
+    let dirs = match &opt.dirs[..] {
+        [] => vec![pick_folder()?],
+        dirs => dirs.to_vec(),
+    };
+    if let [dir] = &dirs[..] {
+        return match opt.wait_until_complete {
+            Some(interval) => wait_until_complete(&opt, dir, Duration::from_secs(interval)),
+            None => check_folder(&opt, dir.clone()),
+        };
+    }
+    if opt.manifest.is_some()
+        || opt.file.is_some()
+        || opt.watch
+        || opt.wait_until_complete.is_some()
+        || opt.md5_from_file.is_some()
+        || opt.report.is_some()
+        || opt.csv.is_some()
+        || opt.missing_out.is_some()
+    {
+        bail!("--manifest, --file, --watch, --wait-until-complete, --md5-from-file, --report, --csv and --missing-out can only be used with a single directory");
+    }
+
     let bold = Style::new().bold();
-    let Opt { dir, force_delete } = StructOpt::from_args();
-    let path = dir
-        .or_else(|| {
-            println!("Please select the folder that contains the update.xml");
-            FileDialog::new().pick_folder()
-        })
-        .ok_or_else(|| anyhow!("aborted"))?;
-    let update_file = path.join("update.xml");
+    let mut results = Vec::new();
+    for dir in dirs {
+        if !opt.quiet {
+            println!("=== {} ===", bold.apply_to(dir.display()));
+        }
+        // A problem with one folder should not prevent checking the others.
+        let status = check_folder(&opt, dir.clone()).unwrap_or_else(|e| {
+            eprintln!("Error: {e:?}");
+            Status::Error
+        });
+        if !opt.quiet {
+            println!();
+        }
+        results.push((dir, status));
+        if status == Status::Interrupted {
+            break;
+        }
+    }
+    if !opt.quiet {
+        println!("Checked {} folders:", results.len());
+        for (dir, status) in &results {
+            println!("- {}: {}", dir.display(), status.describe());
+        }
+    }
+    Ok(results
+        .into_iter()
+        .map(|(_, status)| status)
+        .max()
+        .unwrap_or(Status::Clean))
+}
+
+/// Checks the maps in a single folder, including removing the corrupt files.
+fn check_folder(opt: &Opt, path: PathBuf) -> Result<Status> {
+    check_folder_summary(opt, path).map(|(status, _)| status)
+}
 
-    println!("Using path: {}", bold.apply_to(path.to_string_lossy()));
+/// Like [`check_folder`], but also returns the summary of the check, unless it stopped before summarizing.
+fn check_folder_summary(opt: &Opt, mut path: PathBuf) -> Result<(Status, Option<Summary>)> {
+    let bold = Style::new().bold();
+    let hash = opt.hash;
+    let update_file = match &opt.manifest {
+        Some(manifest) => manifest.clone(),
+        None => {
+            let update_file = Manifest::find(&path);
+            if update_file.exists() {
+                update_file
+            } else {
+                let update_file = choose_manifest(&path, &opt.interrupted)?;
+                // The maps are stored next to the manifest.
+                path = update_file.parent().unwrap_or(&path).to_path_buf();
+                update_file
+            }
+        }
+    };
 
-    let manifest = Manifest::open(&update_file)?;
-    let countries = manifest.countries()?;
-    let country_count = countries.len();
-    let files: Vec<_> = countries.into_iter().flat_map(|c| c.files()).collect();
+    if !opt.quiet {
+        info!("Using path: {}", bold.apply_to(path.to_string_lossy()));
+    }
+
+    let cache_file = path.join(CACHE_FILE);
+    let cache = Arc::new(if opt.no_cache {
+        Cache::default()
+    } else {
+        Cache::load(&cache_file)
+    });
+
+    let manifest = open_manifest(&update_file)?;
+    if let Err(e) = manifest.verify_self() {
+        if opt.strict_manifest {
+            return Err(e);
+        }
+        warn!("{e}");
+    }
+    if let Some(expected) = &opt.expect_region {
+        if manifest.region_name().to_lowercase() != expected.to_lowercase() {
+            bail!(
+                "The manifest is for region {}, not {expected}",
+                manifest.region_name()
+            );
+        }
+    }
+    let sums = match &opt.md5_from_file {
+        Some(path) => Some(md5sums::read(path)?),
+        None => None,
+    };
+    let log = match &opt.log {
+        Some(log) => Some(Arc::new(LogWriter::open(log, manifest.region_name())?)),
+        None => None,
+    };
+    let processor = Processor {
+        hash,
+        fast: opt.fast,
+        ignore_size: opt.ignore_size,
+        deep: opt.deep,
+        cache: Some(cache.clone()),
+        xattr_cache: opt.xattr_cache,
+        threads: opt.threads_io.unwrap_or(opt.threads),
+        hashers: opt.threads_hash.map(|n| Arc::new(Hashers::new(n))),
+        log: log.clone(),
+        dedup: opt.dedup.then(|| Arc::new(Dedup::default())),
+        fail_fast: opt.fail_fast,
+        min_age: Duration::from_secs(opt.min_age),
+        newer_than: opt.newer_than,
+        file_timeout: opt.file_timeout.map(Duration::from_secs),
+        buffer_size: opt.buffer_size * 1024,
+        retries: opt.retries,
+        timings: opt.timings.then(|| Arc::new(Timings::default())),
+        verbose: opt.verbose,
+        cancel: Some(opt.interrupted.clone()),
+        byte_budget: opt
+            .max_concurrent_bytes
+            .map(|bytes| Arc::new(ByteBudget::new(bytes))),
+        hashed_bytes: Arc::default(),
+    };
+    if let Some(filename) = &opt.file {
+        return check_file(
+            &manifest,
+            &processor,
+            &path,
+            filename,
+            &opt.filename_pattern,
+        )
+        .map(|status| (status, None));
+    }
+    let (countries, without_info) = manifest.countries();
+    if !without_info.is_empty() && !opt.quiet {
+        let ids: Vec<_> = without_info.iter().map(|id| id.to_string()).collect();
+        warn!(
+            "{} sales regions had no catalog info: {}\n(the files of these countries cannot be verified)",
+            bold.apply_to(without_info.len()),
+            ids.join(", ")
+        );
+    }
+    let mut country_count = countries.len();
+    let groups = GroupFilter {
+        skip_groups: opt.skip_groups.clone(),
+        skip_speech: opt.no_speech,
+        pattern: opt.filename_pattern.clone(),
+    };
+    let expected_names = expected_filenames(&countries, &groups);
+    let checked_countries: Vec<_> = countries
+        .iter()
+        .copied()
+        .filter(|c| opt.countries.is_empty() || opt.countries.contains(&c.id))
+        .collect();
+    let (mut files, mut manifest_problems) = expected_files(countries, &groups);
+    for id in select_countries(&mut files, &opt.countries) {
+        warn!("No country found with id: {}", bold.apply_to(id));
+    }
+    select_files(&mut files, &opt.include, &opt.exclude)?;
+    let ignore_file = opt
+        .ignore_file
+        .clone()
+        .unwrap_or_else(|| path.join(IGNORE_FILE));
+    let ignored = match opt.ignore_file.is_some() || ignore_file.exists() {
+        true => Some(read_ignore_file(&ignore_file)?),
+        false => None,
+    };
+    if let Some(ignored) = &ignored {
+        files.retain(|f| !ignored.is_match(&f.filename));
+        manifest_problems.retain(|p| p.filename().map_or(true, |f| !ignored.is_match(f)));
+    }
+    // The files that are reported on, the list itself is consumed while checking.
+    let selected = files.clone();
+    if !opt.countries.is_empty()
+        || !opt.include.is_empty()
+        || !opt.exclude.is_empty()
+        || ignored.is_some()
+    {
+        country_count = files
+            .iter()
+            .map(|f| f.country_id)
+            .collect::<HashSet<_>>()
+            .len();
+    }
     let total_size = files.iter().map(|f| f.packedsize).sum();
+    if let Some(budget) = opt.max_unpacked {
+        check_unpacked_budget(&files, &manifest, budget)?;
+    }
+    if opt.list {
+        for file in &files {
+            let checksum = file.checksum(hash).value;
+            println!("{}\t{}\t{checksum}", file.filename, file.packedsize);
+        }
+        return Ok((Status::Clean, None));
+    }
 
-    println!(
-        "Found maps for region: {} ({} countries in {} files)",
-        bold.apply_to(manifest.region_name()),
-        bold.apply_to(country_count),
-        bold.apply_to(files.len())
+    if !opt.quiet {
+        eprintln!(
+            "Found maps for region: {} ({} countries in {} files)",
+            bold.apply_to(manifest.region_name()),
+            bold.apply_to(country_count),
+            bold.apply_to(files.len())
+        );
+        eprintln!("Total size: {}", bold.apply_to(HumanBytes(total_size)));
+        eprintln!(
+            "Manifest version: {}, generated: {}",
+            bold.apply_to(manifest.version().unwrap_or("unknown")),
+            bold.apply_to(manifest.generated().unwrap_or("unknown"))
+        );
+    }
+
+    if let Some(hash) = hash {
+        if files
+            .iter()
+            .any(|f| f.checksum(Some(hash)).algorithm != hash)
+        {
+            warn!("Manifest does not contain a {hash} checksum for every file\n(the strongest available checksum will be used for those files)");
+        }
+    }
+
+    let mut zip_files = if opt.no_progress || opt.quiet {
+        find_zip_files(&path, &NoProgress, opt.strict)?
+    } else {
+        let spinner = ProgressBar::with_draw_target(None, ProgressDrawTarget::stderr()).with_style(
+            ProgressStyle::default_spinner()
+                .template(SCAN_TEMPLATE)
+                .unwrap(),
+        );
+        spinner.enable_steady_tick(Duration::from_millis(100));
+        let zip_files = find_zip_files(&path, &spinner, opt.strict);
+        spinner.finish_and_clear();
+        zip_files?
+    };
+
+    // Case-insensitive file systems may report a different case than the manifest uses.
+    let mut case_problems = Vec::new();
+    let wrong_case = match_ignoring_case(&mut zip_files, &expected_names);
+    if opt.ignore_case || cfg!(any(windows, target_os = "macos")) {
+        for (name, expected) in wrong_case {
+            warn!(
+                "File {} only matches {} when ignoring case",
+                bold.apply_to(name),
+                bold.apply_to(expected)
+            );
+        }
+    } else if opt.fix_case
+        && !opt.read_only()
+        && opt.wait_until_complete.is_none()
+        && !wrong_case.is_empty()
+    {
+        for (name, expected) in wrong_case {
+            rename(path.join(&name), path.join(&expected))
+                .with_context(|| format!("Could not rename {name} to {expected}"))?;
+            warn!(
+                "Renamed {} to {}",
+                bold.apply_to(name),
+                bold.apply_to(expected)
+            );
+        }
+        zip_files = find_zip_files(&path, &NoProgress, opt.strict)?;
+    } else {
+        // Runs that only report never rename, the files are still checked under the name in the manifest.
+        if opt.fix_case && opt.dry_run {
+            for (name, expected) in &wrong_case {
+                println!("Would rename: {name} to {expected}");
+            }
+        }
+        case_problems.extend(
+            wrong_case
+                .into_iter()
+                .map(|(found, expected)| Problem::WrongCase { found, expected }),
+        );
+    }
+
+    // Ignored files are not unexpected either.
+    if let Some(ignored) = &ignored {
+        zip_files.retain(|name, _| !ignored.is_match(name));
+    }
+
+    if !opt.quiet {
+        eprintln!(
+            "Found {} relevant files in path",
+            bold.apply_to(zip_files.len())
+        );
+    }
+    let parts = find_zip_parts(&path)?;
+    let on_disk = size_on_disk(&files, &zip_files, &parts);
+    if !opt.quiet {
+        eprintln!(
+            "Expected {}, have {} on disk",
+            bold.apply_to(HumanBytes(total_size)),
+            bold.apply_to(HumanBytes(on_disk))
+        );
+        eprintln!("Performing integrity check...");
+    }
+
+    // Missing optional files are neither ok nor a problem.
+    let file_count = files
+        .iter()
+        .filter(|f| {
+            !f.optional || zip_files.contains_key(&f.filename) || parts.contains_key(&f.filename)
+        })
+        .count();
+    let (files, mut checked) = match &sums {
+        Some(sums) => md5sums::cross_check(files, sums),
+        None => (files, Vec::new()),
+    };
+    let hash_start = Instant::now();
+    let (files, split) = split_files(files, &zip_files, &parts);
+    let (mut files, size_problems) = check_sizes(files, &zip_files, &processor);
+    checked.extend(size_problems);
+    files.extend(split);
+    if let Some(log) = &log {
+        checked.iter().for_each(|p| log.problem(p));
+    }
+    let hash_size = if opt.ignore_size {
+        size_on_disk(&files, &zip_files, &parts)
+    } else {
+        files.iter().map(|f| f.packedsize).sum()
+    };
+    let skip_hashing = opt.fail_fast && !(manifest_problems.is_empty() && checked.is_empty());
+    let analyzed = if skip_hashing {
+        CheckResult::default()
+    } else if opt.no_progress {
+        analyze(files, &zip_files, &parts, &processor, &NoProgress)?
+    } else if opt.progress_format == ProgressFormat::Machine {
+        let progress = MachineProgress::new(hash_size, MACHINE_PROGRESS_INTERVAL);
+        let analyzed = analyze(files, &zip_files, &parts, &processor, &progress)?;
+        progress.finish();
+        analyzed
+    } else if opt.quiet {
+        analyze(files, &zip_files, &parts, &processor, &NoProgress)?
+    } else {
+        // Draw on stderr, so the results on stdout can be redirected.
+        let bar = ProgressBar::with_draw_target(Some(hash_size), ProgressDrawTarget::stderr())
+            .with_style(
+                ProgressStyle::default_bar()
+                    .template(PROGRESS_TEMPLATE)
+                    .unwrap(),
+            );
+        // Keep the elapsed time and ETA up to date while a large file is being opened.
+        bar.enable_steady_tick(Duration::from_millis(500));
+        let progress = FileCountProgress::new(bar, files.len() as u64);
+        let analyzed = analyze(files, &zip_files, &parts, &processor, &progress)?;
+        progress.bar().abandon();
+        analyzed
+    };
+    let hash_elapsed = hash_start.elapsed();
+    let ok_so_far = analyzed.ok.len();
+    checked.extend(analyzed.problems);
+    checked.retain(|problem| match problem {
+        Problem::WrongSignature {
+            filename,
+            algorithm,
+            ..
+        } if opt.allow_signature.contains(filename) => {
+            warn!(
+                "File {} has a different {algorithm} than expected, which is allowed",
+                bold.apply_to(filename)
+            );
+            false
+        }
+        _ => true,
+    });
+    if processor.cancelled() {
+        save_cache(&cache, &cache_file, opt);
+        if let Some(log) = &log {
+            log.flush()?;
+        }
+        println!(
+            "Interrupted after checking {} of {file_count} files, {ok_so_far} ok",
+            ok_so_far + checked.len()
+        );
+        for problem in &checked {
+            println!("- {problem}");
+        }
+        return Ok((Status::Interrupted, None));
+    }
+    let ok_count = file_count.saturating_sub(checked.len());
+    if let Some(log) = &log {
+        case_problems.iter().for_each(|p| log.problem(p));
+    }
+    let mut problems = manifest_problems;
+    problems.extend(case_problems);
+    problems.extend(checked);
+    problems.extend(find_unexpected_files(&zip_files, &expected_names));
+    save_cache(&cache, &cache_file, opt);
+    if let Some(log) = &log {
+        log.flush()?;
+    }
+
+    if let Some(report) = &opt.report {
+        Report::new(manifest.region_name(), file_count, &problems).write(report)?;
+    }
+
+    if let Some(csv) = &opt.csv {
+        write_csv(
+            csv,
+            &inventory(&checked_countries, &selected, &zip_files, &problems),
+        )?;
+    }
+
+    if let Some(missing_out) = &opt.missing_out {
+        let mut missing = problems.missing_files();
+        missing.sort_unstable();
+        let contents: String = missing.iter().map(|f| format!("{f}\n")).collect();
+        write(missing_out, contents).context("Could not write the list of missing files")?;
+    }
+
+    if opt.fail_fast {
+        if let Some(problem) = problems.first() {
+            println!("Stopped at the first problem: {problem}");
+            return Ok((Status::Problems, None));
+        }
+    }
+
+    if opt.format == OutputFormat::Github {
+        eprintln!("{}", summary_line(&problems, ok_count));
+        for problem in &problems {
+            println!("{}", problem.github_annotation());
+        }
+        let status = if problems.is_empty() {
+            Status::Clean
+        } else {
+            Status::Problems
+        };
+        return Ok((status, None));
+    }
+
+    if opt.emit_redownload {
+        eprintln!("{}", summary_line(&problems, ok_count));
+        let mut redownload = problems.corrupt_files();
+        redownload.extend(problems.truncated_files());
+        redownload.extend(problems.missing_files());
+        redownload.sort_unstable();
+        for filename in &redownload {
+            println!("{filename}");
+        }
+        let status = if problems.is_empty() {
+            Status::Clean
+        } else {
+            Status::Problems
+        };
+        return Ok((status, None));
+    }
+
+    // While waiting for the download to complete, every round prints its own line.
+    if opt.quiet && opt.wait_until_complete.is_none() {
+        println!("{}", summary_line(&problems, ok_count));
+    } else if !opt.quiet {
+        println!();
+    }
+
+    let statuses = country_statuses(&checked_countries, &selected, &problems);
+    let summary = Summary::new(
+        manifest.region_name(),
+        country_count,
+        file_count,
+        total_size,
+        on_disk,
+        ok_count,
+        &problems,
+    );
+    let mut tally: HashMap<_, _> = selected
+        .iter()
+        .map(|f| (f.filename.clone(), true))
+        .collect();
+    for filename in problems.iter().filter_map(|p| p.filename()) {
+        if let Some(ok) = tally.get_mut(filename) {
+            *ok = false;
+        }
+    }
+    let status = handle_problems(
+        problems,
+        &manifest,
+        &statuses,
+        &groups,
+        &zip_files,
+        opt,
+        path.clone(),
+    )?;
+    if !opt.quiet {
+        println!("\n{}", bold.apply_to("Summary"));
+        println!("{summary}");
+        println!();
+        summary.print_colored();
+        let hashed = processor.hashed_bytes.load(Ordering::Relaxed);
+        if hashed > 0 {
+            print_throughput(hashed, hash_elapsed);
+        }
+    }
+    if let Some(timings) = &processor.timings {
+        print_timings(timings);
+    }
+    // Ctrl-C at a question aborts the removal of the corrupt files.
+    if opt.interrupted.load(Ordering::SeqCst) {
+        return Ok((Status::Interrupted, None));
+    }
+    if opt.watch {
+        return watch(&path, selected, &processor, tally).map(|status| (status, None));
+    }
+    Ok((status, Some(summary)))
+}
+
+/// Checks `dir` again after every `interval` until there are no problems left, Ctrl-C is pressed or the maximum number
+/// of attempts is reached. Only the last round prints the full report, the others print what is left to download.
+fn wait_until_complete(opt: &Opt, dir: &Path, interval: Duration) -> Result<Status> {
+    let round = Opt {
+        quiet: true,
+        ..opt.clone()
+    };
+    let mut previous = None;
+    let mut attempt = 1;
+    loop {
+        let last = opt.max_attempts.map_or(false, |max| attempt >= max);
+        let (status, summary) =
+            check_folder_summary(if last { opt } else { &round }, dir.to_path_buf())?;
+        if status == Status::Clean && !last && !opt.quiet {
+            if let Some(summary) = &summary {
+                println!("{summary}");
+                println!();
+                summary.print_colored();
+            }
+        }
+        if status != Status::Problems {
+            return Ok(status);
+        }
+        if last {
+            eprintln!("Still not complete after {attempt} attempt(s), giving up");
+            return Ok(status);
+        }
+        let left = match &summary {
+            Some(summary) => remaining_line(summary, previous.as_ref()),
+            None => "Not complete yet".to_string(),
+        };
+        eprintln!(
+            "{left}, checking again in {}",
+            humantime::format_duration(interval)
+        );
+        previous = summary;
+        // Sleep in small steps, so Ctrl-C does not have to wait for the next attempt.
+        let deadline = Instant::now() + interval;
+        while Instant::now() < deadline {
+            if opt.interrupted.load(Ordering::SeqCst) {
+                return Ok(Status::Interrupted);
+            }
+            sleep(Duration::from_millis(100));
+        }
+        attempt += 1;
+    }
+}
+
+/// Describes the files that are left to download, and how many fewer (or more) problems there are than in the
+/// `previous` round.
+fn remaining_line(summary: &Summary, previous: Option<&Summary>) -> String {
+    let problems: usize = summary.problems.values().sum();
+    let other = problems - summary.missing - summary.incomplete - summary.corrupt;
+    let mut line = format!(
+        "Still {} missing, {} incomplete, {} corrupt",
+        summary.missing, summary.incomplete, summary.corrupt
     );
+    if other > 0 {
+        line.push_str(&format!(", {other} other problem(s)"));
+    }
+    let before: usize = previous.map_or(problems, |p| p.problems.values().sum());
+    if problems < before {
+        line.push_str(&format!(" ({} fewer than before)", before - problems));
+    } else if problems > before {
+        line.push_str(&format!(" ({} more than before)", problems - before));
+    }
+    line
+}
 
-    println!("Total size: {}", bold.apply_to(HumanBytes(total_size)));
+/// Remembers the verified files for the next run. The cache only saves time, so a maps folder that cannot be written
+/// to (e.g. a read-only share) does not fail the check.
+fn save_cache(cache: &Cache, path: &Path, opt: &Opt) {
+    if opt.read_only() {
+        return;
+    }
+    if let Err(e) = cache.save(path) {
+        warn!("{e:#}");
+    }
+}
 
-    let zip_files = find_zip_files(&path)?;
+/// Fails when the combined unpacked size of `files` exceeds `budget`, listing the unpacked size of every country to
+/// show what to trim.
+fn check_unpacked_budget(files: &[ZipFile], manifest: &Manifest, budget: u64) -> Result<()> {
+    let total: u64 = files.iter().map(|f| f.unpackedsize).sum();
+    if total <= budget {
+        return Ok(());
+    }
+    let mut by_country = HashMap::new();
+    for file in files {
+        *by_country.entry(file.country_id).or_insert(0) += file.unpackedsize;
+    }
+    let mut by_country: Vec<_> = by_country.into_iter().collect();
+    by_country.sort_by_key(|(id, size)| (Reverse(*size), *id));
+    let names = manifest.country_names();
+    eprintln!("Unpacked size per country:");
+    for (id, size) in by_country {
+        let name = names.get(&id).copied().unwrap_or("Unknown");
+        eprintln!("- {name}: {}", HumanBytes(size));
+    }
+    bail!(
+        "The unpacked maps take {}, which exceeds the budget of {}",
+        HumanBytes(total),
+        HumanBytes(budget)
+    )
+}
+
+/// The number of files listed by `--timings`.
+const SLOWEST_FILES: usize = 10;
 
+/// Prints how fast the files were hashed, to compare storage backends.
+fn print_throughput(bytes: u64, elapsed: Duration) {
+    let rate = bytes as f64 / elapsed.as_secs_f64().max(0.001);
     println!(
-        "Found {} relevant files in path",
-        bold.apply_to(zip_files.len())
+        "Hashed {} in {} ({}/s)",
+        HumanBytes(bytes),
+        humantime::format_duration(Duration::from_secs(elapsed.as_secs())),
+        HumanBytes(rate as u64)
     );
+}
+
+fn print_timings(timings: &Timings) {
+    let slowest = timings.slowest(SLOWEST_FILES);
+    if slowest.is_empty() {
+        return;
+    }
+    println!("\n{}", Style::new().bold().apply_to("Slowest files"));
+    for timing in slowest {
+        println!(
+            "- {}: {:.2?} for {} ({}/s)",
+            timing.filename,
+            timing.duration,
+            HumanBytes(timing.size),
+            HumanBytes(timing.bytes_per_sec() as u64)
+        );
+    }
+}
 
-    println!("Performing integrity check...");
-    let problems = analyze(files, &zip_files, total_size);
+/// Checks a single file of the manifest, ignoring the cache, and prints the result.
+fn check_file(
+    manifest: &Manifest,
+    processor: &Processor,
+    path: &Path,
+    filename: &str,
+    pattern: &FilenamePattern,
+) -> Result<Status> {
+    let expected_file = match manifest.find_file(filename, pattern) {
+        Some(file) => file?,
+        None => bail!("File {filename} is not part of the manifest"),
+    };
+    let entry = read_dir(path)
+        .with_context(|| format!("Could not read directory {}", path.display()))?
+        .filter_map(|e| e.ok())
+        .find(|e| e.file_name() == filename);
+    let result = match entry {
+        None => Err(Problem::NotFound {
+            filename: filename.to_string(),
+        }),
+        Some(entry) => Processor {
+            cache: None,
+            ..processor.clone()
+        }
+        .process_file(&NoProgress, &entry, expected_file),
+    };
+    match result {
+        Ok(_) => {
+            println!("{}: {}", filename, Style::new().green().apply_to("OK"));
+            Ok(Status::Clean)
+        }
+        Err(problem) => {
+            println!("{problem}");
+            Ok(Status::Problems)
+        }
+    }
+}
 
-    println!();
+/// Checks the expected files again whenever they are created or modified, until Ctrl-C is pressed. `tally` holds
+/// whether every expected file was ok after the initial check.
+fn watch(
+    path: &Path,
+    files: Vec<ZipFile>,
+    processor: &Processor,
+    mut tally: HashMap<String, bool>,
+) -> Result<Status> {
+    let (sender, receiver) = channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = sender.send(event);
+    })
+    .context("Could not watch the directory")?;
+    watcher
+        .watch(path, RecursiveMode::NonRecursive)
+        .context("Could not watch the directory")?;
 
-    handle_problems(problems, force_delete, path)?;
+    let files: HashMap<_, _> = files.into_iter().map(|f| (f.filename.clone(), f)).collect();
+    let mut pending = BTreeSet::new();
+    eprintln!("Watching for changes, press Ctrl-C to stop...");
+    while !processor.cancelled() {
+        match receiver.recv_timeout(Duration::from_millis(500)) {
+            Ok(event) => {
+                let event: notify::Event = event.context("Could not watch the directory")?;
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    pending.extend(
+                        event
+                            .paths
+                            .iter()
+                            .filter_map(|p| Some(p.file_name()?.to_string_lossy().into_owned()))
+                            .filter(|name| files.contains_key(name)),
+                    );
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if pending.is_empty() {
+            continue;
+        }
+        let zip_files = find_zip_files(path, &NoProgress, false)?;
+        for filename in std::mem::take(&mut pending) {
+            let (expected_file, actual_file) =
+                match (files.get(&filename), zip_files.get(&filename)) {
+                    (Some(expected_file), Some(actual_file)) => {
+                        (expected_file.clone(), actual_file)
+                    }
+                    _ => continue,
+                };
+            match processor.process_file(&NoProgress, actual_file, expected_file) {
+                Ok(_) => {
+                    println!("ok {filename}");
+                    tally.insert(filename.clone(), true);
+                }
+                // Check again once the downloader is done with it.
+                Err(Problem::StillDownloading { .. }) => {
+                    pending.insert(filename.clone());
+                }
+                Err(problem) => {
+                    println!("- {problem}");
+                    tally.insert(filename.clone(), false);
+                }
+            }
+        }
+        let ok = tally.values().filter(|ok| **ok).count();
+        println!("{ok} of {} files ok", tally.len());
+    }
 
-    Ok(())
+    let ok = tally.values().filter(|ok| **ok).count();
+    println!("\nStopped watching, {ok} of {} files ok", tally.len());
+    Ok(if ok == tally.len() {
+        Status::Clean
+    } else {
+        Status::Problems
+    })
 }
 
-fn find_zip_files(path: &Path) -> Result<HashMap<String, DirEntry>> {
-    read_dir(path)
-        .context("Could not read directory entries")?
-        .filter_map(|f| match f {
-            Err(e) => Some(Err(e.into())),
-            Ok(e) if e.path().extension()? == "zip" => Some(Ok((
-                e.path().file_name()?.to_string_lossy().into_owned(),
-                e,
-            ))),
-            _ => None,
-        })
-        .collect::<Result<HashMap<String, DirEntry>>>()
-        .context("Error while reading directory entries")
+#[cfg(feature = "gui")]
+fn pick_folder() -> Result<PathBuf> {
+    if !display_available() {
+        bail!("No display available for the folder picker, pass the directory as an argument");
+    }
+    eprintln!("Please select the folder that contains the update.xml");
+    FileDialog::new()
+        .pick_folder()
+        .ok_or_else(|| anyhow::anyhow!("aborted"))
 }
 
-fn analyze(
-    files: Vec<ZipFile>,
-    zip_files: &HashMap<String, DirEntry>,
-    total_size: u64,
-) -> Vec<Problem> {
-    let bar = ProgressBar::new(total_size).with_style(
-        ProgressStyle::default_bar()
-            .template("[{elapsed_precise}] {bar:40} {bytes:.bold}/{total_bytes:.bold}")
-            .unwrap(),
-    );
+#[cfg(not(feature = "gui"))]
+fn pick_folder() -> Result<PathBuf> {
+    bail!("No directory given, pass the directory as an argument")
+}
+
+/// Windows and macOS always have a display, other platforms need an X11 or Wayland session.
+#[cfg(feature = "gui")]
+fn display_available() -> bool {
+    cfg!(any(windows, target_os = "macos"))
+        || env::var_os("DISPLAY").is_some()
+        || env::var_os("WAYLAND_DISPLAY").is_some()
+}
 
-    let problems: Vec<Problem> = files
-        .into_par_iter()
-        .map_with(bar.clone(), |bar, expected_file| {
-            match zip_files.get(&expected_file.filename) {
-                None => Some(Problem::NotFound {
-                    filename: expected_file.filename,
-                }),
-                Some(actual_file) => process_file(bar, actual_file, expected_file),
+/// Asks the user which manifest to use when there is none in `dir`, but there are some in its subdirectories.
+fn choose_manifest(dir: &Path, interrupted: &AtomicBool) -> Result<PathBuf> {
+    let mut found = Manifest::search(dir);
+    let mut response = String::new();
+    match found.len() {
+        0 => bail!(
+            "No update.xml found in {} or its subdirectories",
+            dir.display()
+        ),
+        1 => {
+            let question = format!(
+                "No update.xml found in {}, use {} instead?",
+                dir.display(),
+                found[0].display()
+            );
+            if !confirm(&mut stdin().lock(), &question, true, interrupted)? {
+                bail!("No update.xml found in {}", dir.display());
+            }
+            Ok(found.remove(0))
+        }
+        n => {
+            eprintln!("No update.xml found in {}, but found:", dir.display());
+            for (i, manifest) in found.iter().enumerate() {
+                eprintln!("{}. {}", i + 1, manifest.display());
             }
+            eprint!("Which one do you want to use? (1-{n}) ");
+            stderr().flush()?;
+            stdin().read_line(&mut response)?;
+            match response.trim().parse::<usize>() {
+                Ok(i) if (1..=n).contains(&i) => Ok(found.remove(i - 1)),
+                _ => bail!("No update.xml selected"),
+            }
+        }
+    }
+}
+
+/// Logs informational messages and warnings to stderr, `RUST_LOG` overrides the level (e.g. `RUST_LOG=debug` shows
+/// what is done with every file).
+fn init_logger(quiet: bool) {
+    let level = if quiet { "warn" } else { "info" };
+    Builder::from_env(Env::default().default_filter_or(format!("maps_download_check={level}")))
+        .format(|buf, record| {
+            let label = match record.level() {
+                Level::Info => return writeln!(buf, "{}", record.args()),
+                Level::Warn => "WARNING",
+                level => level.as_str(),
+            };
+            let red = record.level() <= Level::Warn;
+            let style = if red {
+                Style::new().red().bold()
+            } else {
+                Style::new().bold()
+            };
+            writeln!(buf, "{}: {}", style.apply_to(label), record.args())
         })
-        .flatten()
-        .collect();
+        .init();
+}
+
+fn is_url(path: &Path) -> bool {
+    path.to_str().map_or(false, |s| {
+        s.starts_with("http://") || s.starts_with("https://")
+    })
+}
+
+/// Opens the manifest at `location`, which may also be a http(s) URL.
+fn open_manifest(location: &Path) -> Result<Manifest> {
+    if !is_url(location) {
+        return Manifest::open(location);
+    }
+    #[cfg(feature = "remote")]
+    return Manifest::fetch(&location.to_string_lossy());
+    #[cfg(not(feature = "remote"))]
+    bail!("Reading the manifest from a URL requires the `remote` feature");
+}
+
+/// Asks a yes/no question, an empty answer picks `default`. When stdin is closed or Ctrl-C was pressed the answer is
+/// always no, so nothing is removed without an explicit answer.
+fn confirm(
+    input: &mut impl BufRead,
+    question: &str,
+    default: bool,
+    interrupted: &AtomicBool,
+) -> Result<bool> {
+    let hint = if default { "(Y/n)" } else { "(y/N)" };
+    loop {
+        eprint!("{question} {hint} ");
+        stderr().flush()?;
+        let mut response = String::new();
+        if input.read_line(&mut response)? == 0 || interrupted.load(Ordering::SeqCst) {
+            eprintln!();
+            return Ok(false);
+        }
+        match &response.trim().to_lowercase()[..] {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => eprintln!("Please answer yes or no."),
+        }
+    }
+}
 
-    bar.abandon();
+/// An answer to [`confirm_each`].
+enum Answer {
+    Yes,
+    No,
+    /// Yes to this and all following questions.
+    All,
+    /// No to this and all following questions.
+    Quit,
+}
 
-    problems
+/// Asks a yes/no question that is repeated for a number of items, an empty answer means yes. When stdin is closed or
+/// Ctrl-C was pressed the answer is always quit.
+fn confirm_each(
+    input: &mut impl BufRead,
+    question: &str,
+    interrupted: &AtomicBool,
+) -> Result<Answer> {
+    loop {
+        eprint!("{question} (Y/n/a/q) ");
+        stderr().flush()?;
+        let mut response = String::new();
+        if input.read_line(&mut response)? == 0 || interrupted.load(Ordering::SeqCst) {
+            eprintln!();
+            return Ok(Answer::Quit);
+        }
+        match &response.trim().to_lowercase()[..] {
+            "" | "y" | "yes" => return Ok(Answer::Yes),
+            "n" | "no" => return Ok(Answer::No),
+            "a" | "all" => return Ok(Answer::All),
+            "q" | "quit" => return Ok(Answer::Quit),
+            _ => eprintln!("Please answer yes, no, all or quit."),
+        }
+    }
 }
 
-fn handle_problems(problems: Vec<Problem>, force_delete: bool, path: PathBuf) -> Result<()> {
+fn summary_line(problems: &[Problem], ok_count: usize) -> String {
+    let missing = problems.missing_files().len();
+    let corrupt = problems.corrupt_files().len();
+    let truncated = problems.truncated_files().len();
+    let mut summary =
+        format!("{corrupt} corrupt, {truncated} incomplete, {missing} missing, {ok_count} ok");
+    let other = problems.len() - missing - corrupt - truncated;
+    if other > 0 {
+        summary.push_str(&format!(", {other} other problem(s)"));
+    }
+    summary
+}
+
+fn handle_problems(
+    mut problems: Vec<Problem>,
+    manifest: &Manifest,
+    statuses: &[CountryStatus],
+    groups: &GroupFilter,
+    zip_files: &HashMap<String, DirEntry>,
+    opt: &Opt,
+    path: PathBuf,
+) -> Result<Status> {
+    let bold = Style::new().bold();
+    opt.sort.sort(&mut problems);
     if problems.is_empty() {
-        println!("No problems encountered, you are good to go!");
-        return Ok(());
+        return Ok(Status::Clean);
     }
 
-    println!("Encountered {} problem(s):", problems.len());
-    if let Some(s) = problems.missing_files_msg() {
-        println!("- {s}")
+    if !opt.quiet {
+        println!("Encountered {} problem(s):", problems.len());
+        let not_downloaded = problems.fully_missing_countries(manifest, groups);
+        for (country, group) in problems.by_country(manifest, groups) {
+            if not_downloaded.contains(&&country[..]) {
+                println!("{}: not downloaded at all", bold.apply_to(country));
+                continue;
+            }
+            println!("{}: {} problem(s)", bold.apply_to(country), group.len());
+            let (missing, other): (Vec<_>, Vec<_>) = group
+                .into_iter()
+                .partition(|p| matches!(p, Problem::NotFound { .. }));
+            let missing: Vec<_> = missing.iter().filter_map(|p| p.filename()).collect();
+            if let Some(s) = missing_files_msg(&missing) {
+                println!("- {s}")
+            }
+            for p in other {
+                if opt.verbose > 0 {
+                    println!("- {}", p.full_message());
+                } else {
+                    println!("- {p}");
+                }
+            }
+        }
+        let incomplete = incomplete(statuses);
+        if !incomplete.is_empty() {
+            println!("Incomplete countries:");
+            for status in incomplete {
+                println!("- {status}");
+            }
+        }
+        let wasted = problems.wasted_bytes();
+        if wasted > 0 {
+            println!(
+                "Corrupt and unexpected files occupy {} on disk.",
+                bold.apply_to(HumanBytes(wasted))
+            );
+        }
+        let truncated = problems.truncated_files().len();
+        if truncated > 0 {
+            println!(
+                "{truncated} file(s) were only partially downloaded, the downloader will resume them."
+            );
+        }
     }
-    for p in problems.other_errors() {
-        println!("- {p}");
+
+    if opt.check_only || opt.wait_until_complete.is_some() {
+        return Ok(Status::Problems);
     }
 
-    let corrupt = problems.corrupt_files();
+    let mut corrupt = problems.corrupt_files();
+    if opt.delete_unexpected {
+        corrupt.extend(problems.unexpected_files());
+    }
     if corrupt.is_empty() {
-        println!(
-            "No corrupt files to remove, restart the downloader to address the missing files."
-        );
-        return Ok(());
+        if !opt.quiet {
+            println!(
+                "No corrupt files to remove, restart the downloader to address the missing and incomplete files."
+            );
+        }
+        return Ok(Status::Problems);
     }
 
-    if !force_delete {
-        print!("Do you want to remove the corrupt files? (Y/n) ");
-        stdout().flush()?;
-        let mut response = String::new();
-        stdin().read_line(&mut response)?;
-        if !matches!(response.trim(), "" | "y" | "Y") {
-            println!("Aborting");
-            return Ok(());
+    let action = if opt.quarantine.is_some() {
+        "Moving"
+    } else {
+        "Removing"
+    };
+
+    if opt.dry_run {
+        for file in corrupt {
+            println!("{action}: {file}");
         }
+        println!("Dry run, no files were removed.");
+        return Ok(Status::Problems);
+    }
+
+    if !opt.force_delete
+        && !opt.interactive
+        && !confirm(
+            &mut stdin().lock(),
+            "Do you want to remove the corrupt files?",
+            true,
+            &opt.interrupted,
+        )?
+    {
+        println!("Aborting");
+        return Ok(Status::Problems);
     }
 
+    if let Some(quarantine) = &opt.quarantine {
+        create_dir_all(quarantine).context("Could not create quarantine directory")?;
+    }
+    let parts = find_zip_parts(&path)?;
+    let mut ask = opt.interactive;
+    let mut removed = Vec::new();
     for file in corrupt {
-        println!("Removing: {file}");
-        remove_file(path.join(file))?;
+        if ask {
+            if let Some(problem) = problems.iter().find(|p| p.filename() == Some(file)) {
+                println!("{problem}");
+            }
+            let verb = if opt.quarantine.is_some() {
+                "Move"
+            } else {
+                "Remove"
+            };
+            match confirm_each(
+                &mut stdin().lock(),
+                &format!("{verb} {file}?"),
+                &opt.interrupted,
+            )? {
+                Answer::Yes => {}
+                Answer::No => continue,
+                Answer::All => ask = false,
+                Answer::Quit => {
+                    println!("Aborting");
+                    break;
+                }
+            }
+        }
+        if !opt.quiet {
+            println!("{action}: {file}");
+        }
+        // A file that was split into parts is removed completely. A file that only matches the manifest when ignoring
+        // case is removed by its name on disk.
+        let names = match (zip_files.get(file), parts.get(file)) {
+            (Some(entry), _) => vec![entry.file_name()],
+            (None, Some(parts)) => parts.iter().map(|(_, entry)| entry.file_name()).collect(),
+            (None, None) => vec![file.into()],
+        };
+        for name in names {
+            let from = path.join(&name);
+            match &opt.quarantine {
+                Some(quarantine) => move_file(&from, &quarantine.join(&name))
+                    .with_context(|| format!("Could not move {}", from.display()))?,
+                None => remove_file(&from)
+                    .with_context(|| format!("Could not remove {}", from.display()))?,
+            }
+            removed.push(from);
+        }
     }
 
-    println!("Done, restart the downloader to address the missing files.");
+    if opt.prune_empty_dirs {
+        for dir in prune_empty_dirs(&path, &removed)? {
+            println!("Removed empty directory: {}", dir.display());
+        }
+    }
+
+    if !opt.quiet {
+        println!("Done, restart the downloader to address the missing and incomplete files.");
+    }
+
+    Ok(Status::Problems)
+}
+
+/// Removes the directories below `path` (but not `path` itself) that are empty after removing the `files`, walking up
+/// from the directory of each file. Directories that were already empty are left alone, and so are directories that
+/// were pruned for an earlier file. Returns the removed directories.
+fn prune_empty_dirs(path: &Path, files: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    for file in files {
+        let mut dir = file.parent();
+        while let Some(current) = dir.filter(|d| d.starts_with(path) && *d != path && d.is_dir()) {
+            let mut entries = read_dir(current)
+                .with_context(|| format!("Could not read {}", current.display()))?;
+            if entries.next().is_some() {
+                break;
+            }
+            remove_dir(current)
+                .with_context(|| format!("Could not remove {}", current.display()))?;
+            removed.push(current.to_path_buf());
+            dir = current.parent();
+        }
+    }
+    Ok(removed)
+}
 
+/// Moves a file, falling back to copy and remove when it cannot be renamed (e.g. across filesystems).
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if rename(from, to).is_err() {
+        copy(from, to).with_context(|| format!("Could not move {}", from.display()))?;
+        remove_file(from)?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(input: &str, default: bool) -> bool {
+        confirm(
+            &mut input.as_bytes(),
+            "Continue?",
+            default,
+            &AtomicBool::new(false),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn confirm_is_no_when_stdin_is_closed() {
+        assert!(!answer("", true));
+    }
+
+    #[test]
+    fn confirm_picks_default_on_empty_answer() {
+        assert!(answer("\n", true));
+        assert!(!answer("\n", false));
+    }
+
+    #[test]
+    fn confirm_accepts_yes_and_no() {
+        assert!(answer("yes\n", false));
+        assert!(answer("y\n", false));
+        assert!(answer("YES\n", false));
+        assert!(!answer("no\n", true));
+        assert!(!answer("n\n", true));
+    }
+
+    #[test]
+    fn confirm_asks_again_after_garbage() {
+        assert!(answer("maybe\ny\n", false));
+        assert!(!answer("maybe\n", true));
+    }
+
+    #[test]
+    fn confirm_is_no_after_ctrl_c() {
+        let interrupted = AtomicBool::new(true);
+        assert!(!confirm(&mut "y\n".as_bytes(), "Continue?", true, &interrupted).unwrap());
+    }
+
+    #[test]
+    fn prune_empty_dirs_only_removes_dirs_of_removed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path();
+        create_dir_all(path.join("keep-me/empty")).unwrap();
+        create_dir_all(path.join("sub/part")).unwrap();
+        let file = path.join("sub/part/1_01.zip");
+        write(&file, "").unwrap();
+        remove_file(&file).unwrap();
+        let removed = prune_empty_dirs(path, &[file, path.join("1_02.zip")]).unwrap();
+        assert_eq!(removed, [path.join("sub/part"), path.join("sub")]);
+        assert!(path.join("keep-me/empty").is_dir());
+    }
+}
@@ -1,40 +1,329 @@
-use anyhow::{Context, Result};
-use console::Style;
+use crate::{
+    problem::Problem,
+    processor::{digest_bytes, HashAlgorithm},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::bufread::GzDecoder;
+use log::debug;
 use quick_xml::de::from_reader;
-use serde::Deserialize;
-use std::{collections::HashMap, fs::File, io::BufReader, path::Path};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::{read_dir, File},
+    io::{self, BufRead, BufReader, Read},
+    iter,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Keeps track of how far the XML parser got, to point at the position of a parse error.
+struct ConsumeCounter<'a> {
+    inner: &'a [u8],
+    consumed: usize,
+}
+
+impl Read for ConsumeCounter<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.consumed += n;
+        Ok(n)
+    }
+}
+
+impl BufRead for ConsumeCounter<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        Ok(self.inner)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt);
+        self.consumed += amt;
+    }
+}
+
+/// Returns the bytes between the start and end tag of the `mapCatalog` element.
+fn catalog_contents(xml: &[u8]) -> Option<&[u8]> {
+    let find = |needle: &[u8], from: usize| {
+        xml[from..]
+            .windows(needle.len())
+            .position(|w| w == needle)
+            .map(|i| from + i)
+    };
+    let start_tag = find(b"<mapCatalog", 0)?;
+    let start = find(b">", start_tag)? + 1;
+    let end = find(b"</mapCatalog>", start)?;
+    Some(&xml[start..end])
+}
+
+/// Describes a byte offset as line and column, with a snippet of the surrounding XML.
+fn describe_position(xml: &[u8], offset: usize) -> String {
+    let offset = offset.min(xml.len());
+    let line_start = xml[..offset]
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(0, |i| i + 1);
+    let line = xml[..offset].iter().filter(|b| **b == b'\n').count() + 1;
+    let column = offset - line_start + 1;
+    let snippet =
+        String::from_utf8_lossy(&xml[offset.saturating_sub(40)..(offset + 40).min(xml.len())]);
+    format!("line {line}, column {column}: {snippet:?}")
+}
 
 impl Manifest {
+    /// Opens the manifest at `path`, which may be gzip compressed. Both the XML `update.xml` and the JSON
+    /// `catalog.json` format are supported.
     pub fn open(path: &Path) -> Result<Self> {
         let file = File::open(path).context("Could not open update.xml in provided path")?;
-        from_reader(BufReader::new(file)).context("Could not parse update.xml")
+        Self::read(BufReader::new(file))
     }
 
-    pub fn countries(&self) -> Result<Vec<&Country>> {
-        let mut country_map: HashMap<_, _> = self
-            .drm_entry
+    /// Downloads the manifest from a http(s) URL, which may be gzip compressed like a local manifest.
+    #[cfg(feature = "remote")]
+    pub fn fetch(url: &str) -> Result<Self> {
+        let response = reqwest::blocking::get(url)
+            .and_then(|r| r.error_for_status())
+            .with_context(|| format!("Could not download the manifest from {url}"))?;
+        let bytes = response
+            .bytes()
+            .with_context(|| format!("Could not download the manifest from {url}"))?;
+        Self::read(&bytes[..])
+    }
+
+    fn read(mut reader: impl BufRead) -> Result<Self> {
+        let compressed = reader
+            .fill_buf()
+            .context("Could not read update.xml")?
+            .starts_with(&GZIP_MAGIC);
+        let mut manifest = if compressed {
+            Self::parse(BufReader::new(GzDecoder::new(reader)))
+        } else {
+            Self::parse(reader)
+        }?;
+        // Otherwise a truncated manifest would look like a complete download of nothing.
+        if manifest.catalog_countries().next().is_none()
+            && manifest.drm_entry.sales_region.regions.is_empty()
+        {
+            bail!("Manifest contains no countries, it is probably incomplete");
+        }
+        manifest.fallback_countries = manifest.missing_countries();
+        Ok(manifest)
+    }
+
+    /// Parses a manifest in JSON format if it starts with a `{`, otherwise in XML format.
+    fn parse(mut reader: impl BufRead) -> Result<Self> {
+        let json = reader
+            .fill_buf()
+            .context("Could not read update.xml")?
+            .iter()
+            .find(|b| !b.is_ascii_whitespace())
+            == Some(&b'{');
+        if json {
+            // The errors of serde_json already include the line and column.
+            return serde_json::from_reader(reader).context("Could not parse catalog.json");
+        }
+        let mut xml = Vec::new();
+        reader
+            .read_to_end(&mut xml)
+            .context("Could not read update.xml")?;
+        let mut counting = ConsumeCounter {
+            inner: &xml[..],
+            consumed: 0,
+        };
+        let mut manifest: Self = from_reader(&mut counting).with_context(|| {
+            format!(
+                "Could not parse update.xml near {}",
+                describe_position(&xml, counting.consumed)
+            )
+        })?;
+        if manifest.drm_entry.map_catalog.checksum.is_some() {
+            manifest.catalog_xml = catalog_contents(&xml).map(<[u8]>::to_vec);
+        }
+        Ok(manifest)
+    }
+
+    /// Verifies the checksum of the map catalog, if the manifest has one, to detect a modified manifest. The checksum
+    /// is only verified in the XML format, because the JSON format is not byte for byte what the vendor published.
+    pub fn verify_self(&self) -> Result<()> {
+        let expected = match &self.drm_entry.map_catalog.checksum {
+            Some(expected) => expected.to_ascii_lowercase(),
+            None => return Ok(()),
+        };
+        let contents = match &self.catalog_xml {
+            Some(contents) => contents,
+            None => {
+                debug!("Not verifying the checksum of the map catalog, it is not in XML format");
+                return Ok(());
+            }
+        };
+        let algorithm = [
+            HashAlgorithm::Md5,
+            HashAlgorithm::Sha1,
+            HashAlgorithm::Sha256,
+        ]
+        .into_iter()
+        .find(|a| a.hex_len() == expected.len())
+        .ok_or_else(|| anyhow!("The map catalog has an invalid checksum: {expected:?}"))?;
+        let got = digest_bytes(algorithm, contents);
+        if got != expected {
+            bail!("The map catalog has {algorithm}: {got}, expected {algorithm}: {expected}, the manifest was modified");
+        }
+        Ok(())
+    }
+
+    /// Creates a placeholder for every country in the sales region that has no entry in the map catalog.
+    fn missing_countries(&self) -> Vec<Country> {
+        let known: HashSet<_> = self.catalog_countries().map(|c| c.id).collect();
+        self.drm_entry
+            .sales_region
+            .regions
+            .iter()
+            .filter(|r| !known.contains(&r.id))
+            .map(|r| Country {
+                id: r.id,
+                name: format!("Region {}", r.id),
+                data_groups: Vec::new(),
+                speech_recognition: None,
+            })
+            .collect()
+    }
+
+    fn catalog_countries(&self) -> impl Iterator<Item = &Country> {
+        self.drm_entry
             .map_catalog
             .regions
             .iter()
             .flat_map(|r| &r.regions)
+    }
+
+    /// Returns the path of the manifest in `dir`, preferring the plain `update.xml` over `update.xml.gz` and
+    /// `catalog.json`.
+    pub fn find(dir: &Path) -> PathBuf {
+        let plain = dir.join("update.xml");
+        ["update.xml.gz", "catalog.json"]
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|path| !plain.exists() && path.exists())
+            .unwrap_or(plain)
+    }
+
+    /// Looks for manifests in `dir` and its direct subdirectories, for when the wrong folder was chosen.
+    pub fn search(dir: &Path) -> Vec<PathBuf> {
+        let subdirs = read_dir(dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_dir());
+        let mut found: Vec<_> = iter::once(dir.to_path_buf())
+            .chain(subdirs)
+            .map(|d| Self::find(&d))
+            .filter(|p| p.is_file())
+            .collect();
+        found.sort();
+        found
+    }
+
+    /// Returns the countries of the sales region, and the ids of the countries without info about their files.
+    /// Countries without an entry in the map catalog are named after their id and have no files, because those cannot
+    /// be derived from the manifest.
+    pub fn countries(&self) -> (Vec<&Country>, Vec<u32>) {
+        let mut country_map: HashMap<_, _> = self
+            .catalog_countries()
+            .chain(&self.fallback_countries)
             .map(|c| (c.id, c))
             .collect();
-        self.drm_entry
+        let countries: Vec<_> = self
+            .drm_entry
             .sales_region
             .regions
             .iter()
-            .filter_map(|r| {
-                let country = country_map.remove(&r.id).map(Ok);
-                if country.is_none() {
-                    eprintln!(
-                        "{}: No info found for country with id: {}\n(country will be skipped in integrity checks)",
-                        Style::new().red().bold().apply_to("WARNING"),
-                        Style::new().bold().apply_to(r.id)
-                    )
-                }
-                country
-            })
-            .collect()
+            .filter_map(|r| country_map.remove(&r.id))
+            .collect();
+        let without_info = countries
+            .iter()
+            .filter(|c| c.data_groups.is_empty() && c.speech_recognition.is_none())
+            .map(|c| c.id)
+            .collect();
+        (countries, without_info)
+    }
+
+    /// Returns all countries in the map catalog, and placeholders for the countries in the sales region that are
+    /// missing from it, without warnings.
+    pub fn all_countries(&self) -> impl Iterator<Item = &Country> {
+        self.catalog_countries().chain(&self.fallback_countries)
+    }
+
+    /// Returns the names of all countries by id.
+    pub fn country_names(&self) -> HashMap<u32, &str> {
+        self.all_countries().map(|c| (c.id, &c.name[..])).collect()
+    }
+
+    /// Looks up the expected file with the given name, in any country of the manifest.
+    pub fn find_file(
+        &self,
+        filename: &str,
+        pattern: &FilenamePattern,
+    ) -> Option<Result<ZipFile<'_>>> {
+        let all = GroupFilter {
+            pattern: pattern.clone(),
+            ..GroupFilter::default()
+        };
+        self.all_countries().find_map(|country| {
+            country
+                .file_infos(&all)
+                .find(|(name, _)| name == filename)
+                .map(|(name, info)| ZipFile::new(country, name, info))
+        })
+    }
+
+    /// Returns the files that should be present for the countries of the sales region, skipping files with invalid info
+    /// in the manifest.
+    pub fn expected_files(&self) -> impl Iterator<Item = ExpectedFile> + '_ {
+        let (countries, _) = self.countries();
+        countries.into_iter().flat_map(|country| {
+            country
+                .file_infos(ALL_GROUPS)
+                .filter_map(move |(filename, info)| {
+                    let file = ZipFile::new(country, filename, info).ok()?;
+                    Some(ExpectedFile {
+                        country: country.name.clone(),
+                        filename: file.filename,
+                        size: file.packedsize,
+                        md5: file.md5.map(str::to_string),
+                        sha1: file.sha1.map(str::to_string),
+                        sha256: file.sha256.map(str::to_string),
+                    })
+                })
+        })
+    }
+
+    /// Serializes the manifest in the JSON `catalog.json` format, which [`Manifest::open`] reads back.
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context("Could not serialize the manifest")
+    }
+
+    /// The version of the manifest, if it has one.
+    pub fn version(&self) -> Option<&str> {
+        let drm_entry = &self.drm_entry;
+        drm_entry
+            .metadata
+            .version
+            .as_deref()
+            .or(drm_entry.map_catalog.metadata.version.as_deref())
+    }
+
+    /// When the manifest was generated, if it says so.
+    pub fn generated(&self) -> Option<&str> {
+        let drm_entry = &self.drm_entry;
+        drm_entry.metadata.generated.as_deref().or(drm_entry
+            .map_catalog
+            .metadata
+            .generated
+            .as_deref())
     }
 
     pub fn region_name(&self) -> &str {
@@ -43,92 +332,413 @@ impl Manifest {
 }
 
 impl Country {
-    pub fn files(&self) -> impl Iterator<Item = ZipFile> {
+    /// Returns the expected files of this country, or an error for every file with invalid info in the manifest.
+    pub fn files<'a: 'f, 'f>(
+        &'a self,
+        filter: &'f GroupFilter,
+    ) -> impl Iterator<Item = Result<ZipFile<'a>>> + 'f {
+        self.file_infos(filter)
+            .map(|(filename, info)| ZipFile::new(self, filename, info))
+    }
+
+    /// Returns the names of the expected files of this country, including those with invalid info.
+    pub fn filenames<'f>(&'f self, filter: &'f GroupFilter) -> impl Iterator<Item = String> + 'f {
+        self.file_infos(filter).map(|(filename, _)| filename)
+    }
+
+    fn file_infos<'a: 'f, 'f>(
+        &'a self,
+        filter: &'f GroupFilter,
+    ) -> impl Iterator<Item = (String, &'a FileInfo)> + 'f {
+        let pattern = &filter.pattern;
         self.data_groups
             .iter()
-            .map(|dg| ZipFile::new(format!("{}_{:02}.zip", self.id, dg.id), &dg.info))
+            .filter(|dg| !filter.skip_groups.contains(&dg.id))
+            .map(|dg| (pattern.data_group(self.id, dg.id), &dg.info))
             .chain(
                 self.speech_recognition
                     .as_ref()
-                    .map(|info| ZipFile::new(format!("{}_speech_recognition.zip", self.id), info)),
+                    .filter(|_| !filter.skip_speech)
+                    .map(|info| (pattern.speech_recognition(self.id), info)),
             )
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// Selects all files of a country.
+const ALL_GROUPS: &GroupFilter = &GroupFilter {
+    skip_groups: Vec::new(),
+    skip_speech: false,
+    pattern: FilenamePattern(Cow::Borrowed(FilenamePattern::DEFAULT)),
+};
+
+/// Selects which files of a country are expected, the default includes all files.
+#[derive(Debug, Default, Clone)]
+pub struct GroupFilter {
+    /// The ids of the data groups that were deliberately not downloaded.
+    pub skip_groups: Vec<u32>,
+    /// The speech recognition package was deliberately not downloaded.
+    pub skip_speech: bool,
+    /// How the files are named.
+    pub pattern: FilenamePattern,
+}
+
+/// A template for the names of the files of a country, for mirrors that rename the files. `{id}` is replaced by the
+/// id of the country, `{dg}` by the id of the data group and `{dg02}` by the id of the data group padded to two
+/// digits. For the speech recognition package, `{dg}` and `{dg02}` are replaced by `speech_recognition`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilenamePattern(Cow<'static, str>);
+
+impl FilenamePattern {
+    pub const DEFAULT: &'static str = "{id}_{dg02}.zip";
+
+    fn data_group(&self, id: u32, group: u32) -> String {
+        self.0
+            .replace("{id}", &id.to_string())
+            .replace("{dg02}", &format!("{group:02}"))
+            .replace("{dg}", &group.to_string())
+    }
+
+    fn speech_recognition(&self, id: u32) -> String {
+        self.0
+            .replace("{id}", &id.to_string())
+            .replace("{dg02}", "speech_recognition")
+            .replace("{dg}", "speech_recognition")
+    }
+}
+
+impl Default for FilenamePattern {
+    fn default() -> Self {
+        FilenamePattern(Cow::Borrowed(Self::DEFAULT))
+    }
+}
+
+impl FromStr for FilenamePattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if !s.contains("{id}") {
+            bail!("filename pattern {s:?} has no {{id}} placeholder");
+        }
+        if !s.contains("{dg}") && !s.contains("{dg02}") {
+            bail!("filename pattern {s:?} has no {{dg}} or {{dg02}} placeholder");
+        }
+        Ok(FilenamePattern(Cow::Owned(s.to_string())))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Manifest {
     drm_entry: DrmEntry,
+    /// Placeholders for countries without an entry in the map catalog.
+    #[serde(skip)]
+    fallback_countries: Vec<Country>,
+    /// The contents of the `mapCatalog` element in `update.xml`, if it has a checksum.
+    #[serde(skip)]
+    catalog_xml: Option<Vec<u8>>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct DrmEntry {
+    #[serde(flatten)]
+    metadata: Metadata,
     map_catalog: MapCatalog,
     sales_region: SalesRegion,
 }
 
-#[derive(Debug, Deserialize)]
+/// Describes the revision of the manifest, not every manifest has this.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Metadata {
+    #[serde(
+        default,
+        deserialize_with = "optional_string_or_number",
+        skip_serializing_if = "Option::is_none"
+    )]
+    version: Option<String>,
+    #[serde(
+        default,
+        alias = "timestamp",
+        alias = "date",
+        deserialize_with = "optional_string_or_number",
+        skip_serializing_if = "Option::is_none"
+    )]
+    generated: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 struct MapCatalog {
-    #[serde(rename = "region")]
+    #[serde(flatten)]
+    metadata: Metadata,
+    /// The checksum of the contents of the element, see [`Manifest::verify_self`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<String>,
+    #[serde(rename = "region", alias = "regions", default)]
     regions: Vec<Continent>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 struct SalesRegion {
     name: String,
 
-    #[serde(rename = "region")]
+    #[serde(rename = "region", alias = "regions", default)]
     regions: Vec<Region>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 struct Region {
     id: u32,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 struct Continent {
-    #[serde(rename = "region")]
+    #[serde(rename = "region", alias = "regions")]
     regions: Vec<Country>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Country {
-    id: u32,
+    pub id: u32,
     pub name: String,
-    #[serde(rename = "dataGroup")]
+    #[serde(rename = "dataGroup", alias = "dataGroups")]
     data_groups: Vec<DataGroup>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     speech_recognition: Option<FileInfo>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct FileInfo {
+    #[serde(alias = "unpackedSize", deserialize_with = "string_or_number")]
     pub unpackedsize: String,
+    #[serde(alias = "packedSize", deserialize_with = "string_or_number")]
     pub packedsize: String,
-    pub md5: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+    /// Only used by older manifests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// The file is not required for the maps to work.
+    #[serde(
+        default,
+        deserialize_with = "bool_or_string",
+        skip_serializing_if = "is_false"
+    )]
+    pub optional: bool,
 }
 
-#[derive(Debug, Deserialize)]
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// Accepts sizes as a number (in JSON) as well as a string (in XML), invalid values are reported when the file is
+/// checked.
+fn string_or_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    struct Visitor;
+
+    impl<'de> de::Visitor<'de> for Visitor {
+        type Value = String;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a string or a number")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<String, E> {
+            Ok(v.to_string())
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<String, E> {
+            Ok(v.to_string())
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<String, E> {
+            Ok(v.to_string())
+        }
+    }
+
+    deserializer.deserialize_any(Visitor)
+}
+
+/// Accepts a boolean (in JSON) as well as `"true"`, `"false"`, `"1"` or `"0"` (in XML).
+fn bool_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    struct Visitor;
+
+    impl<'de> de::Visitor<'de> for Visitor {
+        type Value = bool;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a boolean")
+        }
+
+        fn visit_bool<E: de::Error>(self, v: bool) -> Result<bool, E> {
+            Ok(v)
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<bool, E> {
+            match v {
+                "true" | "1" => Ok(true),
+                "false" | "0" => Ok(false),
+                _ => Err(E::invalid_value(de::Unexpected::Str(v), &self)),
+            }
+        }
+    }
+
+    deserializer.deserialize_any(Visitor)
+}
+
+fn optional_string_or_number<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    string_or_number(deserializer).map(Some)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 struct DataGroup {
     #[serde(flatten)]
     info: FileInfo,
     id: u32,
 }
 
+#[derive(Debug, Clone)]
 pub struct ZipFile<'a> {
+    pub country_id: u32,
     pub filename: String,
     pub packedsize: u64,
-    pub md5: &'a str,
+    pub unpackedsize: u64,
+    pub md5: Option<&'a str>,
+    pub sha1: Option<&'a str>,
+    pub sha256: Option<&'a str>,
+    /// A missing optional file is not a problem.
+    pub optional: bool,
+}
+
+/// A file that should be present according to the manifest, independent of the manifest's lifetime.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ExpectedFile {
+    /// The name of the country the file belongs to.
+    pub country: String,
+    pub filename: String,
+    /// The size of the file in bytes.
+    pub size: u64,
+    pub md5: Option<String>,
+    pub sha1: Option<String>,
+    pub sha256: Option<String>,
+}
+
+/// An expected checksum of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checksum<'a> {
+    pub algorithm: HashAlgorithm,
+    pub value: &'a str,
+}
+
+impl Checksum<'_> {
+    /// Whether the value is a digest of the algorithm, in lowercase or uppercase hexadecimal digits.
+    pub fn is_valid(&self) -> bool {
+        self.value.len() == self.algorithm.hex_len()
+            && self.value.bytes().all(|b| b.is_ascii_hexdigit())
+    }
 }
 
 impl<'a> ZipFile<'a> {
-    fn new(filename: String, info: &'a FileInfo) -> Self {
-        ZipFile {
+    fn new(country: &Country, filename: String, info: &'a FileInfo) -> Result<Self> {
+        let parse_size = |field, value: &str| {
+            value.parse().map_err(|_| {
+                anyhow!(
+                    "Invalid {field} {value:?} for file {filename} of {} in update.xml",
+                    country.name
+                )
+            })
+        };
+        if info.md5.is_none() && info.sha1.is_none() && info.sha256.is_none() {
+            return Err(anyhow!(
+                "No checksum for file {filename} of {} in update.xml",
+                country.name
+            ));
+        }
+        let file = ZipFile {
+            country_id: country.id,
+            packedsize: parse_size("packedsize", &info.packedsize)?,
+            unpackedsize: parse_size("unpackedsize", &info.unpackedsize)?,
             filename,
-            packedsize: info.packedsize.parse().expect("Could not parse packedsize"),
-            md5: &info.md5,
+            md5: info.md5.as_deref(),
+            sha1: info.sha1.as_deref(),
+            sha256: info.sha256.as_deref(),
+            optional: info.optional,
+        };
+        // An invalid checksum would be reported as a mismatch of every file.
+        if let Some(checksum) = file.checksums().find(|c| !c.is_valid()) {
+            return Err(Problem::BadManifestChecksum {
+                filename: file.filename,
+                algorithm: checksum.algorithm,
+                value: checksum.value.to_string(),
+            }
+            .into());
         }
+        Ok(file)
+    }
+
+    /// Returns the checksums in the manifest, from weakest to strongest.
+    pub fn checksums(&self) -> impl Iterator<Item = Checksum<'a>> {
+        [
+            (HashAlgorithm::Md5, self.md5),
+            (HashAlgorithm::Sha1, self.sha1),
+            (HashAlgorithm::Sha256, self.sha256),
+        ]
+        .into_iter()
+        .filter_map(|(algorithm, value)| {
+            Some(Checksum {
+                algorithm,
+                value: value?,
+            })
+        })
+    }
+
+    /// Returns the checksum to verify this file with, falling back to the strongest checksum in the manifest when no
+    /// algorithm is requested or the manifest does not provide it.
+    pub fn checksum(&self, requested: Option<HashAlgorithm>) -> Checksum<'a> {
+        requested
+            .and_then(|requested| self.checksums().find(|c| c.algorithm == requested))
+            .or_else(|| self.checksums().last())
+            .expect("every file has a checksum")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(xml: &str) -> Result<Manifest> {
+        Manifest::read(xml.as_bytes())
+    }
+
+    #[test]
+    fn sales_region_without_catalog_entry_gets_placeholder() {
+        let manifest = read(
+            r#"<update><drmEntry>
+<mapCatalog><region><region id="1" name="Germany">
+<dataGroup id="1" unpackedsize="10" packedsize="124" md5="a0e16f62528decfacbeeca04e225fe45"/>
+</region></region></mapCatalog>
+<salesRegion name="Europe"><region id="1"/><region id="3"/></salesRegion>
+</drmEntry></update>"#,
+        )
+        .unwrap();
+        let (countries, without_info) = manifest.countries();
+        let names: Vec<_> = countries.iter().map(|c| (c.id, &c.name[..])).collect();
+        assert_eq!(names, [(1, "Germany"), (3, "Region 3")]);
+        assert_eq!(without_info, [3]);
+    }
+
+    #[test]
+    fn manifest_without_regions_is_an_error() {
+        let xml = r#"<update><drmEntry><mapCatalog></mapCatalog>
+<salesRegion name="Europe"></salesRegion></drmEntry></update>"#;
+        let error = read(xml).unwrap_err();
+        assert!(error.to_string().contains("Manifest contains no countries"));
+        let json = r#"{"drmEntry": {"mapCatalog": {"regions": []}, "salesRegion": {"name": "Europe", "regions": []}}}"#;
+        let error = read(json).unwrap_err();
+        assert!(error.to_string().contains("Manifest contains no countries"));
     }
 }
@@ -0,0 +1,48 @@
+use crate::{manifest::ZipFile, problem::Problem};
+use anyhow::{bail, Context, Result};
+use std::{collections::HashMap, fs::read_to_string, path::Path};
+
+/// Reads a checksums file in the format of `md5sum` (`<hash>  <filename>`), returns the lowercase hashes by filename.
+pub fn read(path: &Path) -> Result<HashMap<String, String>> {
+    let contents = read_to_string(path).context("Could not read checksums file")?;
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| match line.split_once(char::is_whitespace) {
+            // Binary mode entries have a `*` in front of the filename.
+            Some((hash, filename)) => Ok((
+                filename.trim_start().trim_start_matches('*').to_string(),
+                hash.to_ascii_lowercase(),
+            )),
+            None => bail!("Invalid line {} in checksums file: {line:?}", i + 1),
+        })
+        .collect()
+}
+
+/// Reports files whose md5 in the manifest differs from the one in the checksums file, without reading them. Returns
+/// the files that still have to be checked.
+pub fn cross_check<'a>(
+    files: Vec<ZipFile<'a>>,
+    sums: &HashMap<String, String>,
+) -> (Vec<ZipFile<'a>>, Vec<Problem>) {
+    let mut remaining = Vec::new();
+    let mut problems = Vec::new();
+    for file in files {
+        match sums.get(&file.filename) {
+            Some(sidecar)
+                if file
+                    .md5
+                    .map_or(false, |md5| !sidecar.eq_ignore_ascii_case(md5)) =>
+            {
+                problems.push(Problem::ChecksumSourceMismatch {
+                    filename: file.filename,
+                    manifest: file.md5.unwrap_or_default().to_string(),
+                    sidecar: sidecar.clone(),
+                })
+            }
+            _ => remaining.push(file),
+        }
+    }
+    (remaining, problems)
+}
@@ -1,53 +1,289 @@
+use crate::{
+    manifest::{GroupFilter, Manifest},
+    processor::HashAlgorithm,
+};
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+};
 use thiserror::Error;
 
-#[derive(Debug, Error)]
+#[derive(Debug, Error, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum Problem {
     #[error("File {filename} was not found")]
     NotFound { filename: String },
+    #[error("File {filename} is incomplete: {got} of {expected} bytes")]
+    Truncated {
+        filename: String,
+        expected: u64,
+        got: u64,
+    },
+    #[error("File {filename} is missing part {part:03}")]
+    MissingPart { filename: String, part: u32 },
+    #[error("File {filename} is empty, the download probably never started")]
+    Empty { filename: String, expected: u64 },
     #[error("File {filename} has size: {got}, expected: {expected}")]
     WrongSize {
         filename: String,
         expected: u64,
         got: u64,
     },
-    #[error("File {filename} has signature: {got:?}, expected: {expected:?}")]
+    #[error(
+        "File {filename} has {algorithm}: {}, expected {algorithm}: {}",
+        abbreviate_md5(got),
+        abbreviate_md5(expected)
+    )]
     WrongSignature {
         filename: String,
+        size: u64,
+        algorithm: HashAlgorithm,
         expected: String,
         got: String,
+        /// The start and end of the file, only read when verbose.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sample: Option<Box<FileSample>>,
+    },
+    #[error("File {filename} has unpacked size: {got}, expected: {expected}")]
+    WrongUnpackedSize {
+        filename: String,
+        size: u64,
+        expected: u64,
+        got: u64,
+    },
+    #[error("File {filename} has md5: {manifest:?} in the manifest, but {sidecar:?} in the checksums file")]
+    ChecksumSourceMismatch {
+        filename: String,
+        manifest: String,
+        sidecar: String,
+    },
+    #[error("File {filename} has an invalid {algorithm} in the manifest: {value:?}")]
+    BadManifestChecksum {
+        filename: String,
+        algorithm: HashAlgorithm,
+        value: String,
+    },
+    #[error("File {filename} could not be read, reading it stalled")]
+    Timeout { filename: String },
+    #[error("File {filename} was modified recently, it is probably still being downloaded")]
+    StillDownloading { filename: String },
+    #[error("File {filename} is not a valid zip archive: {reason}")]
+    CorruptArchive {
+        filename: String,
+        size: u64,
+        reason: String,
+    },
+    #[error("File {found} only matches {expected} when ignoring case")]
+    WrongCase { found: String, expected: String },
+    #[error("File {filename} is not part of the manifest")]
+    Unexpected { filename: String, size: u64 },
+    #[error("File {filename} could not be read: {source}")]
+    IoError {
+        filename: String,
+        #[serde(serialize_with = "serialize_io_error")]
+        source: io::Error,
     },
     #[error(transparent)]
-    Error(#[from] anyhow::Error),
+    Error(
+        #[from]
+        #[serde(serialize_with = "serialize_error")]
+        anyhow::Error,
+    ),
+}
+
+impl Problem {
+    /// The name of the file this problem is about, if any.
+    pub fn filename(&self) -> Option<&str> {
+        match self {
+            Problem::NotFound { filename }
+            | Problem::Truncated { filename, .. }
+            | Problem::Empty { filename, .. }
+            | Problem::MissingPart { filename, .. }
+            | Problem::WrongSize { filename, .. }
+            | Problem::WrongSignature { filename, .. }
+            | Problem::WrongUnpackedSize { filename, .. }
+            | Problem::CorruptArchive { filename, .. }
+            | Problem::ChecksumSourceMismatch { filename, .. }
+            | Problem::BadManifestChecksum { filename, .. }
+            | Problem::StillDownloading { filename }
+            | Problem::Timeout { filename }
+            | Problem::Unexpected { filename, .. }
+            | Problem::IoError { filename, .. }
+            | Problem::WrongCase {
+                expected: filename, ..
+            } => Some(filename),
+            Problem::Error(_) => None,
+        }
+    }
+
+    /// The size of the file on disk, if known.
+    pub fn size(&self) -> Option<u64> {
+        match self {
+            Problem::Truncated { got: size, .. }
+            | Problem::WrongSize { got: size, .. }
+            | Problem::WrongSignature { size, .. }
+            | Problem::WrongUnpackedSize { size, .. }
+            | Problem::CorruptArchive { size, .. }
+            | Problem::Unexpected { size, .. } => Some(*size),
+            _ => None,
+        }
+    }
+
+    /// The kind of problem, as used in the `type` field of the JSON report.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Problem::NotFound { .. } => "not_found",
+            Problem::Truncated { .. } => "truncated",
+            Problem::Empty { .. } => "empty",
+            Problem::MissingPart { .. } => "missing_part",
+            Problem::WrongSize { .. } => "wrong_size",
+            Problem::WrongSignature { .. } => "wrong_signature",
+            Problem::WrongUnpackedSize { .. } => "wrong_unpacked_size",
+            Problem::CorruptArchive { .. } => "corrupt_archive",
+            Problem::ChecksumSourceMismatch { .. } => "checksum_source_mismatch",
+            Problem::BadManifestChecksum { .. } => "bad_manifest_checksum",
+            Problem::StillDownloading { .. } => "still_downloading",
+            Problem::Timeout { .. } => "timeout",
+            Problem::WrongCase { .. } => "wrong_case",
+            Problem::Unexpected { .. } => "unexpected",
+            Problem::IoError { .. } => "io_error",
+            Problem::Error(_) => "error",
+        }
+    }
+
+    /// The message of this problem, with full checksums instead of abbreviated ones.
+    pub fn full_message(&self) -> String {
+        match self {
+            Problem::WrongSignature {
+                filename,
+                algorithm,
+                expected,
+                got,
+                sample,
+                ..
+            } => {
+                let mut message = format!(
+                    "File {filename} has {algorithm}: {got}, expected {algorithm}: {expected}"
+                );
+                if let Some(sample) = sample {
+                    message.push_str(&format!(
+                        "\n  first bytes: {}\n  last bytes:  {}",
+                        sample.head, sample.tail
+                    ));
+                }
+                message
+            }
+            _ => self.to_string(),
+        }
+    }
+
+    /// Formats this problem as a GitHub Actions workflow command, which shows it as an annotation. Missing, incomplete
+    /// and unexpected files are warnings, other problems are errors.
+    pub fn github_annotation(&self) -> String {
+        let (level, title) = match self {
+            Problem::NotFound { .. } => ("warning", "Missing file"),
+            Problem::Truncated { .. } | Problem::Empty { .. } | Problem::MissingPart { .. } => {
+                ("warning", "Incomplete file")
+            }
+            Problem::Unexpected { .. } => ("warning", "Unexpected file"),
+            Problem::WrongSize { .. }
+            | Problem::WrongSignature { .. }
+            | Problem::WrongUnpackedSize { .. }
+            | Problem::CorruptArchive { .. } => ("error", "Corrupt file"),
+            _ => ("error", "Problem"),
+        };
+        let message = self
+            .full_message()
+            .replace('%', "%25")
+            .replace('\r', "%0D")
+            .replace('\n', "%0A");
+        format!("::{level} title={title}::{message}")
+    }
+}
+
+/// The first and last bytes of a file in hexadecimal, to see what a corrupt file contains, e.g. an HTML error page or
+/// zeros.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileSample {
+    pub head: String,
+    pub tail: String,
+}
+
+/// Shortens a checksum to its first 8 and last 4 characters, e.g. `a1b2c3d4…ef01`. Other values are kept as is.
+pub fn abbreviate_md5(hash: &str) -> String {
+    if hash.len() > 12 && hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        format!("{}…{}", &hash[..8], &hash[hash.len() - 4..])
+    } else {
+        hash.to_string()
+    }
+}
+
+/// Summarizes the missing files in a single line.
+pub fn missing_files_msg(filenames: &[&str]) -> Option<String> {
+    if filenames.is_empty() {
+        return None;
+    }
+    let mut s = format!(
+        "{} missing files: {}",
+        filenames.len(),
+        filenames.join(", ")
+    );
+    if s.len() > 80 {
+        s.truncate(77);
+        s.push_str("...");
+    }
+    Some(s)
+}
+
+/// Serializes an error as an object with its message, because an internally tagged enum cannot hold a plain string.
+fn serialize_error<S: Serializer>(error: &anyhow::Error, serializer: S) -> Result<S::Ok, S::Error> {
+    let mut s = serializer.serialize_struct("Error", 1)?;
+    s.serialize_field("message", &format!("{error:#}"))?;
+    s.end()
+}
+
+fn serialize_io_error<S: Serializer>(error: &io::Error, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(error)
 }
 
 pub trait ProblemList {
+    fn missing_files(&self) -> Vec<&str>;
     fn missing_files_msg(&self) -> Option<String>;
     fn other_errors(&self) -> Vec<&Problem>;
     fn corrupt_files(&self) -> Vec<&str>;
+    fn truncated_files(&self) -> Vec<&str>;
+    fn unexpected_files(&self) -> Vec<&str>;
+    /// The disk space occupied by corrupt and unexpected files.
+    fn wasted_bytes(&self) -> u64;
+    /// Groups the problems by the name of their country, problems without a known country are grouped under "Unknown".
+    /// The country is looked up by the filenames `filter` generates, regardless of the groups it skips.
+    fn by_country(
+        &self,
+        manifest: &Manifest,
+        filter: &GroupFilter,
+    ) -> BTreeMap<String, Vec<&Problem>>;
+    /// Returns the names of the countries of which every expected file is missing, i.e. that were not downloaded at
+    /// all.
+    fn fully_missing_countries<'a>(
+        &self,
+        manifest: &'a Manifest,
+        filter: &GroupFilter,
+    ) -> Vec<&'a str>;
 }
 
 impl ProblemList for [Problem] {
-    fn missing_files_msg(&self) -> Option<String> {
-        let filenames: Vec<_> = self
-            .iter()
+    fn missing_files(&self) -> Vec<&str> {
+        self.iter()
             .filter_map(|p| match p {
                 Problem::NotFound { filename } => Some(&filename[..]),
                 _ => None,
             })
-            .collect();
-        if filenames.is_empty() {
-            return None;
-        }
-        let mut s = format!(
-            "{} missing files: {}",
-            filenames.len(),
-            filenames.join(", ")
-        );
-        if s.len() > 80 {
-            s.truncate(77);
-            s.push_str("...");
-        }
-        Some(s)
+            .collect()
+    }
+
+    fn missing_files_msg(&self) -> Option<String> {
+        missing_files_msg(&self.missing_files())
     }
 
     fn other_errors(&self) -> Vec<&Problem> {
@@ -59,11 +295,87 @@ impl ProblemList for [Problem] {
     fn corrupt_files(&self) -> Vec<&str> {
         self.iter()
             .filter_map(|p| match p {
-                Problem::WrongSignature { filename, .. } | Problem::WrongSize { filename, .. } => {
-                    Some(&filename[..])
-                }
+                Problem::WrongSignature { filename, .. }
+                | Problem::WrongSize { filename, .. }
+                | Problem::WrongUnpackedSize { filename, .. }
+                | Problem::CorruptArchive { filename, .. } => Some(&filename[..]),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn truncated_files(&self) -> Vec<&str> {
+        self.iter()
+            .filter_map(|p| match p {
+                Problem::Truncated { filename, .. }
+                | Problem::Empty { filename, .. }
+                | Problem::MissingPart { filename, .. } => Some(&filename[..]),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn unexpected_files(&self) -> Vec<&str> {
+        self.iter()
+            .filter_map(|p| match p {
+                Problem::Unexpected { filename, .. } => Some(&filename[..]),
                 _ => None,
             })
             .collect()
     }
+
+    fn wasted_bytes(&self) -> u64 {
+        self.iter()
+            .map(|p| match p {
+                Problem::WrongSize { got: size, .. }
+                | Problem::WrongSignature { size, .. }
+                | Problem::WrongUnpackedSize { size, .. }
+                | Problem::CorruptArchive { size, .. }
+                | Problem::Unexpected { size, .. } => *size,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    fn by_country(
+        &self,
+        manifest: &Manifest,
+        filter: &GroupFilter,
+    ) -> BTreeMap<String, Vec<&Problem>> {
+        let all = GroupFilter {
+            pattern: filter.pattern.clone(),
+            ..GroupFilter::default()
+        };
+        let names: HashMap<_, _> = manifest
+            .all_countries()
+            .flat_map(|c| c.filenames(&all).map(move |f| (f, &c.name[..])))
+            .collect();
+        let mut groups: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for problem in self {
+            let name = problem
+                .filename()
+                .and_then(|f| names.get(f))
+                .map_or("Unknown", |name| name);
+            groups.entry(name.to_string()).or_default().push(problem);
+        }
+        groups
+    }
+
+    fn fully_missing_countries<'a>(
+        &self,
+        manifest: &'a Manifest,
+        filter: &GroupFilter,
+    ) -> Vec<&'a str> {
+        let missing: HashSet<_> = self.missing_files().into_iter().collect();
+        let mut names: Vec<_> = manifest
+            .all_countries()
+            .filter(|country| {
+                let mut filenames = country.filenames(filter).peekable();
+                filenames.peek().is_some() && filenames.all(|f| missing.contains(&f[..]))
+            })
+            .map(|country| &country.name[..])
+            .collect();
+        names.sort_unstable();
+        names
+    }
 }
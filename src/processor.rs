@@ -1,56 +1,1042 @@
-use crate::{manifest::ZipFile, problem::Problem};
-use anyhow::Result;
-use indicatif::ProgressBar;
+use crate::{
+    cache::{read_stamp, write_stamp, Cache, CacheEntry},
+    check_log::LogWriter,
+    manifest::{Checksum, ZipFile},
+    problem::{FileSample, Problem},
+    progress::{Progress, ProgressReader, RetryProgress},
+};
+use anyhow::{bail, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
 use std::{
-    fs::{DirEntry, File},
-    io::copy,
-    path::Path,
+    cmp::Reverse,
+    collections::HashMap,
+    fmt,
+    fs::{DirEntry, File, Metadata},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{channel, sync_channel, Receiver, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, sleep},
+    time::{Duration, Instant, SystemTime},
 };
+use zip::{result::ZipResult, ZipArchive};
+
+/// The algorithm used to verify the contents of a downloaded file.
+/// The algorithms are ordered from weakest to strongest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub const VARIANTS: &'static [&'static str] = &["md5", "sha1", "sha256"];
+
+    /// The number of hexadecimal digits of a digest.
+    pub fn hex_len(self) -> usize {
+        match self {
+            Self::Md5 => 32,
+            Self::Sha1 => 40,
+            Self::Sha256 => 64,
+        }
+    }
+}
+
+impl Default for HashAlgorithm {
+    fn default() -> Self {
+        Self::Md5
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "md5" => Ok(Self::Md5),
+            "sha1" => Ok(Self::Sha1),
+            "sha256" => Ok(Self::Sha256),
+            _ => bail!("unknown hash algorithm: {s}"),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Md5 => "md5",
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+        })
+    }
+}
+
+/// A file that matched its manifest entry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct VerifiedFile {
+    pub filename: String,
+    pub size: u64,
+    /// The checksum that matched the manifest, `None` if only the size was compared.
+    pub checksum: Option<(HashAlgorithm, String)>,
+}
+
+/// The data of an expected file on disk: a single file, or the parts of a split file in order.
+struct FileData {
+    paths: Vec<PathBuf>,
+    /// The combined size of the files.
+    size: u64,
+    /// The most recent modification time of the files.
+    mtime: SystemTime,
+    /// The device and inode of a single file, see [`Dedup`].
+    id: Option<(u64, u64)>,
+}
+
+/// Verifies downloaded files against their manifest entries.
+#[derive(Debug, Default, Clone)]
+pub struct Processor {
+    /// The preferred hash algorithm, the strongest checksum in the manifest when `None`.
+    pub hash: Option<HashAlgorithm>,
+    /// Only compare file sizes, without hashing the files.
+    pub fast: bool,
+    /// Do not compare file sizes with the manifest, only checksums.
+    pub ignore_size: bool,
+    /// Also verify the unpacked size of each archive.
+    pub deep: bool,
+    /// Skip hashing files that were verified before and have not been modified since.
+    pub cache: Option<Arc<Cache>>,
+    /// Also store the digest of verified files in an extended attribute of the file (only supported on Unix).
+    pub xattr_cache: bool,
+    /// The number of files to check in parallel, 0 uses one thread per CPU core.
+    pub threads: usize,
+    /// Hash the data on these threads instead of on the threads that read the files.
+    pub hashers: Option<Arc<Hashers>>,
+    /// Writes the result of every processed file.
+    pub log: Option<Arc<LogWriter>>,
+    /// Hash files that are hard links to the same data only once.
+    pub dedup: Option<Arc<Dedup>>,
+    /// Stop checking files as soon as a problem is found.
+    pub fail_fast: bool,
+    /// Skip files that were modified more recently than this, because the downloader is probably still writing them.
+    pub min_age: Duration,
+    /// Assume that files that were modified longer ago than this are ok, to only check the files of an incremental
+    /// download.
+    pub newer_than: Option<Duration>,
+    /// Give up on a file when reading it stalls for this long, e.g. on a stale network mount.
+    pub file_timeout: Option<Duration>,
+    /// The size of the read buffer used for hashing, 0 uses [`DEFAULT_BUFFER_SIZE`].
+    pub buffer_size: usize,
+    /// The number of times to retry reading a file after a transient I/O error.
+    pub retries: u32,
+    /// Records how long every file took to check.
+    pub timings: Option<Arc<Timings>>,
+    /// Print every verified file (1), including the time it took (2).
+    pub verbose: u8,
+    /// Stop starting to check files once this is set, e.g. by Ctrl-C.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Limits the total size of the files that are checked at the same time.
+    pub byte_budget: Option<Arc<ByteBudget>>,
+    /// Counts the bytes of the files that were hashed, excluding the files that were skipped.
+    pub hashed_bytes: Arc<AtomicU64>,
+}
+
+impl Processor {
+    pub fn cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .map_or(false, |cancel| cancel.load(Ordering::SeqCst))
+    }
+
+    /// Whether a file modified at `mtime` is judged by its age alone, i.e. it is still being downloaded (see
+    /// `min_age`) or assumed to be ok (see `newer_than`).
+    pub fn judged_by_age(&self, mtime: SystemTime) -> bool {
+        let age = age(mtime);
+        age < self.min_age || self.newer_than.map_or(false, |newer_than| age > newer_than)
+    }
+
+    pub fn process_file(
+        &self,
+        progress: &dyn Progress,
+        actual_file: &DirEntry,
+        expected_file: ZipFile,
+    ) -> Result<VerifiedFile, Problem> {
+        self.process(progress, expected_file, |expected_file| {
+            self.try_process_file(progress, actual_file, expected_file)
+        })
+    }
+
+    /// Verifies a file that was split into `parts` (numbered from 1), by hashing the parts in order. The unpacked size
+    /// is not verified, even when checking deeply.
+    pub fn process_parts(
+        &self,
+        progress: &dyn Progress,
+        parts: &[(u32, DirEntry)],
+        expected_file: ZipFile,
+    ) -> Result<VerifiedFile, Problem> {
+        self.process(progress, expected_file, |expected_file| {
+            self.try_process_parts(progress, parts, expected_file)
+        })
+    }
+
+    /// Logs and reports the result of `check`.
+    fn process<'a>(
+        &self,
+        progress: &dyn Progress,
+        expected_file: ZipFile<'a>,
+        check: impl FnOnce(ZipFile<'a>) -> Result<Option<(HashAlgorithm, String)>>,
+    ) -> Result<VerifiedFile, Problem> {
+        let filename = expected_file.filename.clone();
+        let size = expected_file.packedsize;
+        let _reservation = self.byte_budget.as_ref().map(|budget| budget.reserve(size));
+        let start = Instant::now();
+        let result = check(expected_file);
+        if let Some(timings) = &self.timings {
+            timings.record(&filename, start.elapsed(), size);
+        }
+        match result {
+            Ok(checksum) => {
+                let checksum_ref = checksum.as_ref().map(|(a, d)| (*a, &d[..]));
+                if let Some(log) = &self.log {
+                    log.ok(&filename, checksum_ref);
+                }
+                if self.verbose > 0 {
+                    let mut line = format!("ok {filename} ({size} bytes)");
+                    if let Some((algorithm, digest)) = checksum_ref {
+                        line.push_str(&format!(" {algorithm}: {digest}"));
+                    }
+                    if self.verbose > 1 {
+                        line.push_str(&format!(" in {:.2?}", start.elapsed()));
+                    }
+                    progress.println(&line);
+                }
+                Ok(VerifiedFile {
+                    filename,
+                    size,
+                    checksum,
+                })
+            }
+            Err(err) => {
+                let problem = err.downcast().unwrap_or_else(Problem::Error);
+                if let Some(log) = &self.log {
+                    log.problem(&problem);
+                }
+                Err(problem)
+            }
+        }
+    }
+
+    fn try_process_file(
+        &self,
+        progress: &dyn Progress,
+        actual_file: &DirEntry,
+        expected_file: ZipFile,
+    ) -> Result<Option<(HashAlgorithm, String)>> {
+        let metadata = actual_file.metadata()?;
+        let data = FileData {
+            paths: vec![actual_file.path()],
+            size: metadata.len(),
+            mtime: metadata.modified()?,
+            id: file_id(&metadata),
+        };
+        self.verify(progress, &data, expected_file)
+    }
+
+    fn try_process_parts(
+        &self,
+        progress: &dyn Progress,
+        parts: &[(u32, DirEntry)],
+        expected_file: ZipFile,
+    ) -> Result<Option<(HashAlgorithm, String)>> {
+        let mut data = FileData {
+            paths: Vec::new(),
+            size: 0,
+            mtime: SystemTime::UNIX_EPOCH,
+            // Hard links are only recognized for whole files.
+            id: None,
+        };
+        for (_, entry) in parts {
+            let metadata = entry.metadata()?;
+            data.paths.push(entry.path());
+            data.size += metadata.len();
+            data.mtime = data.mtime.max(metadata.modified()?);
+        }
+        if let Some((part, _)) = (1..).zip(parts).find(|(n, (part, _))| n != part) {
+            progress.inc(self.progress_size(&expected_file, &data));
+            return Err(Problem::MissingPart {
+                filename: expected_file.filename,
+                part,
+            }
+            .into());
+        }
+        self.verify(progress, &data, expected_file)
+    }
+
+    /// The number of bytes the progress bar tracks for a file: the actual size when ignoring sizes.
+    fn progress_size(&self, expected_file: &ZipFile, data: &FileData) -> u64 {
+        if self.ignore_size {
+            data.size
+        } else {
+            expected_file.packedsize
+        }
+    }
+
+    /// Verifies the age, size, signature and checksum of the data of an expected file, and the unpacked size when
+    /// checking deeply.
+    fn verify(
+        &self,
+        progress: &dyn Progress,
+        data: &FileData,
+        expected_file: ZipFile,
+    ) -> Result<Option<(HashAlgorithm, String)>> {
+        let zip_size = data.size;
+        let size = self.progress_size(&expected_file, data);
+        let mtime = data.mtime;
+        let age = age(mtime);
+        if age < self.min_age {
+            debug!("{}: modified {age:?} ago, skipping", expected_file.filename);
+            progress.inc(size);
+            return Err(Problem::StillDownloading {
+                filename: expected_file.filename,
+            }
+            .into());
+        }
+        if self.newer_than.map_or(false, |newer_than| age > newer_than) {
+            debug!(
+                "{}: modified {age:?} ago, assuming it is ok",
+                expected_file.filename
+            );
+            progress.inc(size);
+            return Ok(None);
+        }
+        // Empty files are reported even when ignoring sizes, they would only fail the signature check.
+        let size_problem = match self.ignore_size && zip_size > 0 {
+            true => None,
+            false => size_problem(&expected_file, zip_size),
+        };
+        if let Some(problem) = size_problem {
+            // Move the bar to the right to indicate progress, even if we didn't actually read any bytes.
+            progress.inc(size);
+            return Err(problem.into());
+        }
+        if self.fast {
+            debug!("{}: size is ok, not hashing", expected_file.filename);
+            progress.inc(size);
+            return Ok(None);
+        }
+        let path = &data.paths[0];
+        let Checksum {
+            algorithm,
+            value: expected,
+        } = expected_file.checksum(self.hash);
+        // Our digests are lowercase, the manifest may use uppercase.
+        let expected_lowercase = expected.to_ascii_lowercase();
+        let expected = expected_lowercase.as_str();
+        // Failed downloads are often not a zip archive at all (e.g. an HTML error page), no need to hash those.
+        if !has_zip_signature(path).map_err(|source| Problem::IoError {
+            filename: expected_file.filename.clone(),
+            source,
+        })? {
+            progress.inc(size);
+            return Err(Problem::WrongSignature {
+                filename: expected_file.filename,
+                size,
+                algorithm,
+                expected: expected.to_string(),
+                got: "not a zip archive".to_string(),
+                sample: self.sample(&data.paths),
+            }
+            .into());
+        }
+        let stamp = match self.xattr_cache {
+            true => read_stamp(path, algorithm, size, mtime),
+            false => None,
+        };
+        let cached = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.get(&expected_file.filename, size, mtime, algorithm))
+            .or_else(|| stamp.clone())
+            .filter(|digest| digest == expected);
+        let file_id = self.dedup.as_ref().and(data.id);
+        let hashed = match (&self.dedup, file_id) {
+            (Some(dedup), Some(id)) => dedup.get(id, algorithm),
+            _ => None,
+        };
+        let got = match cached.or(hashed) {
+            Some(digest) => {
+                debug!(
+                    "{}: {algorithm} known from an earlier check, not hashing",
+                    expected_file.filename
+                );
+                progress.inc(size);
+                digest
+            }
+            None => {
+                progress.hashing(&expected_file.filename);
+                let digest = self.get_digest_with_retries(progress, &data.paths, size, algorithm);
+                progress.hashed(&expected_file.filename);
+                self.hashed_bytes.fetch_add(size, Ordering::Relaxed);
+                digest
+            }
+            .map_err(|source| match source.kind() {
+                io::ErrorKind::TimedOut => Problem::Timeout {
+                    filename: expected_file.filename.clone(),
+                },
+                _ => Problem::IoError {
+                    filename: expected_file.filename.clone(),
+                    source,
+                },
+            })?,
+        };
+        if let (Some(dedup), Some(id)) = (&self.dedup, file_id) {
+            dedup.insert(id, algorithm, got.clone());
+        }
+        if self.xattr_cache && got == expected && stamp.as_deref() != Some(expected) {
+            let entry = CacheEntry {
+                size,
+                mtime,
+                algorithm,
+                digest: got.clone(),
+            };
+            if let Err(e) = write_stamp(path, &entry) {
+                debug!(
+                    "{}: could not store the digest: {e}",
+                    expected_file.filename
+                );
+            }
+        }
+        if let Some(cache) = &self.cache {
+            if got == expected {
+                let entry = CacheEntry {
+                    size,
+                    mtime,
+                    algorithm,
+                    digest: got.clone(),
+                };
+                cache.insert(expected_file.filename.clone(), entry);
+            } else {
+                cache.remove(&expected_file.filename);
+            }
+        }
+        if got != expected {
+            let expected = expected.to_string();
+            return Err(Problem::WrongSignature {
+                filename: expected_file.filename,
+                size,
+                algorithm,
+                got,
+                expected,
+                sample: self.sample(&data.paths),
+            }
+            .into());
+        }
+        // Split files are not opened as an archive, so their unpacked size is not verified.
+        if self.deep && data.paths.len() == 1 {
+            // The progress bar already accounted for this file while hashing, reading the central directory is cheap.
+            let expected = expected_file.unpackedsize;
+            let file = File::open(path).map_err(|source| Problem::IoError {
+                filename: expected_file.filename.clone(),
+                source,
+            })?;
+            let corrupt = |reason: String| Problem::CorruptArchive {
+                filename: expected_file.filename.clone(),
+                size,
+                reason,
+            };
+            let mut archive = ZipArchive::new(file).map_err(|e| corrupt(e.to_string()))?;
+            if archive.is_empty() {
+                return Err(corrupt("archive has no entries".to_string()).into());
+            }
+            let got = get_unpacked_size(&mut archive).map_err(|e| corrupt(e.to_string()))?;
+            if got != expected {
+                return Err(Problem::WrongUnpackedSize {
+                    filename: expected_file.filename,
+                    size,
+                    expected,
+                    got,
+                }
+                .into());
+            }
+        }
+        Ok(Some((algorithm, got)))
+    }
+
+    /// Reads the start and end of a corrupt file when verbose, failing to do so is not worth reporting.
+    fn sample(&self, paths: &[PathBuf]) -> Option<Box<FileSample>> {
+        if self.verbose == 0 {
+            return None;
+        }
+        read_sample(paths)
+            .map(Box::new)
+            .map_err(|e| debug!("{}: could not read a sample: {e}", paths[0].display()))
+            .ok()
+    }
+
+    fn buffer_size(&self) -> usize {
+        match self.buffer_size {
+            0 => DEFAULT_BUFFER_SIZE,
+            n => n,
+        }
+    }
+
+    fn get_digest_with_retries(
+        &self,
+        progress: &dyn Progress,
+        paths: &[PathBuf],
+        size: u64,
+        algorithm: HashAlgorithm,
+    ) -> io::Result<String> {
+        debug!(
+            "{}: hashing {} file(s) with {algorithm}",
+            paths[0].display(),
+            paths.len()
+        );
+        let buffer_size = self.buffer_size();
+        let progress = RetryProgress::new(progress);
+        let mut attempt = 0;
+        loop {
+            let result = match self.file_timeout {
+                Some(timeout) => get_digest_with_timeout(
+                    &progress,
+                    paths,
+                    algorithm,
+                    buffer_size,
+                    self.hashers.clone(),
+                    timeout,
+                ),
+                None => get_digest(
+                    &progress,
+                    paths,
+                    algorithm,
+                    buffer_size,
+                    self.hashers.as_deref(),
+                ),
+            };
+            match result {
+                Err(e) if attempt < self.retries && is_transient(&e) => {
+                    attempt += 1;
+                    debug!("{}: {e}, retry {attempt}", paths[0].display());
+                    sleep(RETRY_DELAY * 2u32.pow(attempt - 1));
+                    progress.restart();
+                }
+                Err(e) => {
+                    // Do not let the progress bar stall behind a file that could not be read.
+                    progress.skip_to(size);
+                    return Err(e);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+enum HashMessage {
+    Read(u64),
+    Done(io::Result<String>),
+}
+
+/// Forwards the progress of a hashing thread over a channel.
+struct ChannelProgress(Mutex<Sender<HashMessage>>);
+
+impl Progress for ChannelProgress {
+    fn inc(&self, bytes: u64) {
+        // The receiver is gone when the file timed out.
+        let _ = self.0.lock().unwrap().send(HashMessage::Read(bytes));
+    }
+}
+
+/// Hashes the files on a separate thread, and gives up when it does not read any data within `timeout`. The thread
+/// is abandoned in that case, because a read from a stale mount cannot be cancelled.
+fn get_digest_with_timeout(
+    progress: &dyn Progress,
+    paths: &[PathBuf],
+    algorithm: HashAlgorithm,
+    buffer_size: usize,
+    hashers: Option<Arc<Hashers>>,
+    timeout: Duration,
+) -> io::Result<String> {
+    let (sender, receiver) = channel();
+    let paths = paths.to_vec();
+    thread::spawn(move || {
+        let channel = ChannelProgress(Mutex::new(sender.clone()));
+        let result = get_digest(&channel, &paths, algorithm, buffer_size, hashers.as_deref());
+        let _ = sender.send(HashMessage::Done(result));
+    });
+    loop {
+        match receiver.recv_timeout(timeout) {
+            Ok(HashMessage::Read(bytes)) => progress.inc(bytes),
+            Ok(HashMessage::Done(result)) => return result,
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "reading the file stalled",
+                ))
+            }
+        }
+    }
+}
+
+/// The digests of the files that were hashed, by device and inode.
+#[derive(Debug, Default)]
+pub struct Dedup {
+    digests: Mutex<HashMap<(u64, u64), (HashAlgorithm, String)>>,
+}
+
+impl Dedup {
+    fn get(&self, id: (u64, u64), algorithm: HashAlgorithm) -> Option<String> {
+        let digests = self.digests.lock().unwrap();
+        let (a, digest) = digests.get(&id)?;
+        (*a == algorithm).then(|| digest.clone())
+    }
+
+    fn insert(&self, id: (u64, u64), algorithm: HashAlgorithm, digest: String) {
+        self.digests.lock().unwrap().insert(id, (algorithm, digest));
+    }
+}
+
+/// A semaphore of bytes: checking a file waits until its size fits in the budget, next to the files that are being
+/// checked already. A file larger than the whole budget is checked on its own.
+#[derive(Debug)]
+pub struct ByteBudget {
+    capacity: u64,
+    available: Mutex<u64>,
+    released: Condvar,
+}
 
-pub fn process_file(
-    bar: &mut ProgressBar,
-    actual_file: &DirEntry,
-    expected_file: ZipFile,
-) -> Option<Problem> {
-    try_process_file(bar, actual_file, expected_file)
-        .err()
-        .map(|err| err.downcast().unwrap_or_else(Problem::Error))
-}
-
-fn try_process_file(
-    bar: &mut ProgressBar,
-    actual_file: &DirEntry,
-    expected_file: ZipFile,
-) -> Result<()> {
+impl ByteBudget {
+    pub fn new(capacity: u64) -> Self {
+        ByteBudget {
+            capacity,
+            available: Mutex::new(capacity),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Blocks until `bytes` are available, which are given back when the reservation is dropped.
+    pub fn reserve(&self, bytes: u64) -> Reservation<'_> {
+        let bytes = bytes.min(self.capacity);
+        let mut available = self.available.lock().unwrap();
+        while *available < bytes {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= bytes;
+        Reservation {
+            budget: self,
+            bytes,
+        }
+    }
+}
+
+/// Bytes reserved from a [`ByteBudget`].
+pub struct Reservation<'a> {
+    budget: &'a ByteBudget,
+    bytes: u64,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        *self.budget.available.lock().unwrap() += self.bytes;
+        self.budget.released.notify_all();
+    }
+}
+
+/// How long it took to check a file.
+#[derive(Debug, Clone)]
+pub struct FileTiming {
+    pub filename: String,
+    pub duration: Duration,
+    pub size: u64,
+}
+
+impl FileTiming {
+    /// The throughput in bytes per second.
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.size as f64 / self.duration.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// The time it took to check every file, collected from all worker threads.
+#[derive(Debug, Default)]
+pub struct Timings {
+    files: Mutex<Vec<FileTiming>>,
+}
+
+impl Timings {
+    fn record(&self, filename: &str, duration: Duration, size: u64) {
+        self.files.lock().unwrap().push(FileTiming {
+            filename: filename.to_string(),
+            duration,
+            size,
+        });
+    }
+
+    /// Returns the `count` files that took the longest, slowest first.
+    pub fn slowest(&self, count: usize) -> Vec<FileTiming> {
+        let mut files = self.files.lock().unwrap().clone();
+        files.sort_by_key(|f| Reverse(f.duration));
+        files.truncate(count);
+        files
+    }
+}
+
+/// Identifies the data of a file by its device and inode, hard links share the same id.
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// The size of the read buffer used for hashing when none is configured.
+pub const DEFAULT_BUFFER_SIZE: usize = 128 * 1024;
+
+/// The delay before the first retry, doubled for every following retry.
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Errors that may go away when reading the file again, e.g. on network-mounted storage.
+fn is_transient(error: &io::Error) -> bool {
+    !matches!(
+        error.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied | io::ErrorKind::TimedOut
+    )
+}
+
+/// Compares the size of a file on disk with the manifest.
+pub fn size_problem(expected_file: &ZipFile, zip_size: u64) -> Option<Problem> {
     let size = expected_file.packedsize;
-    let zip_size = actual_file.metadata()?.len();
-    if zip_size != size {
-        // Move the bar to the right to indicate progress, even if we didn't actually read any bytes.
-        bar.inc(size);
-        return Err(Problem::WrongSize {
-            filename: expected_file.filename,
+    let filename = expected_file.filename.clone();
+    if zip_size == 0 && size > 0 {
+        Some(Problem::Empty {
+            filename,
+            expected: size,
+        })
+    } else if zip_size < size {
+        Some(Problem::Truncated {
+            filename,
+            expected: size,
+            got: zip_size,
+        })
+    } else if zip_size > size {
+        Some(Problem::WrongSize {
+            filename,
             expected: size,
             got: zip_size,
+        })
+    } else {
+        None
+    }
+}
+
+/// The signatures of a local file header and of the end of central directory record of an empty archive.
+const ZIP_SIGNATURES: [[u8; 4]; 2] = [*b"PK\x03\x04", *b"PK\x05\x06"];
+
+/// The number of bytes of the start and end of a corrupt file that are shown when verbose.
+const SAMPLE_SIZE: u64 = 64;
+
+/// Reads the start of the first and the end of the last of `paths`, the parts of a split file.
+fn read_sample(paths: &[PathBuf]) -> io::Result<FileSample> {
+    let mut head = Vec::new();
+    File::open(&paths[0])?
+        .take(SAMPLE_SIZE)
+        .read_to_end(&mut head)?;
+    let mut file = File::open(&paths[paths.len() - 1])?;
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(SAMPLE_SIZE)))?;
+    let mut tail = Vec::new();
+    file.take(SAMPLE_SIZE).read_to_end(&mut tail)?;
+    let hex = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    Ok(FileSample {
+        head: hex(&head),
+        tail: hex(&tail),
+    })
+}
+
+fn has_zip_signature(path: &Path) -> io::Result<bool> {
+    let mut signature = [0; 4];
+    match File::open(path)?.read_exact(&mut signature) {
+        Ok(()) => Ok(ZIP_SIGNATURES.contains(&signature)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Hashes the files in `paths` one after the other, as a single file.
+fn get_digest(
+    progress: &dyn Progress,
+    paths: &[PathBuf],
+    algorithm: HashAlgorithm,
+    buffer_size: usize,
+    hashers: Option<&Hashers>,
+) -> io::Result<String> {
+    match hashers {
+        Some(hashers) => hashers.hash_file(progress, paths, algorithm, buffer_size),
+        None => digest_with(algorithm, |hasher| {
+            read_chunks(progress, paths, buffer_size, |chunk| {
+                hasher.write_all(chunk)
+            })
+        }),
+    }
+}
+
+/// Returns the digest of `bytes` in lowercase hexadecimal digits.
+pub fn digest_bytes(algorithm: HashAlgorithm, bytes: &[u8]) -> String {
+    digest_with(algorithm, |hasher| hasher.write_all(bytes)).expect("hashing bytes cannot fail")
+}
+
+/// The time since `mtime`, a modification time in the future also counts as recent.
+fn age(mtime: SystemTime) -> Duration {
+    SystemTime::now().duration_since(mtime).unwrap_or_default()
+}
+
+/// Computes the digest of the data that `feed` writes to the hasher.
+fn digest_with(
+    algorithm: HashAlgorithm,
+    feed: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> io::Result<String> {
+    match algorithm {
+        HashAlgorithm::Md5 => {
+            let mut context = md5::Context::new();
+            feed(&mut context)?;
+            Ok(format!("{:x}", context.compute()))
+        }
+        HashAlgorithm::Sha1 => {
+            let mut hasher = Sha1::new();
+            feed(&mut hasher)?;
+            Ok(format!("{:x}", hasher.finalize()))
         }
-        .into());
+        HashAlgorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            feed(&mut hasher)?;
+            Ok(format!("{:x}", hasher.finalize()))
+        }
+    }
+}
+
+/// Reads the files in `paths` one after the other in chunks of `buffer_size`, larger buffers than `io::copy` uses
+/// give a much higher throughput on fast drives.
+fn read_chunks(
+    progress: &dyn Progress,
+    paths: &[PathBuf],
+    buffer_size: usize,
+    mut consume: impl FnMut(&[u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut reader: Box<dyn Read> = Box::new(io::empty());
+    for path in paths {
+        reader = Box::new(reader.chain(File::open(path)?));
     }
-    let expected = expected_file.md5;
-    let got = get_md5(bar, &actual_file.path())?;
-    if got != expected {
-        let expected = expected.to_string();
-        return Err(Problem::WrongSignature {
-            filename: expected_file.filename,
-            got,
-            expected,
+    let mut reader = ProgressReader::new(reader, progress);
+    let mut buffer = vec![0; buffer_size];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(n) => consume(&buffer[..n])?,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
         }
-        .into());
     }
-    Ok(())
 }
 
-fn get_md5(bar: &mut ProgressBar, path: &Path) -> Result<String> {
-    let file = File::open(path)?;
-    let mut context = md5::Context::new();
-    copy(&mut bar.wrap_read(file), &mut context)?;
-    Ok(format!("{:x}", context.compute()))
+/// The number of chunks a reader may be ahead of the hasher of a file.
+const CHUNKS_IN_FLIGHT: usize = 4;
+
+struct HashJob {
+    algorithm: HashAlgorithm,
+    chunks: Receiver<Vec<u8>>,
+    digest: Sender<io::Result<String>>,
+}
+
+/// A pool of threads that hash the data read by other threads, so reading and hashing can use a different number of
+/// threads.
+#[derive(Debug)]
+pub struct Hashers {
+    jobs: Mutex<Sender<HashJob>>,
+}
+
+impl Hashers {
+    pub fn new(threads: usize) -> Self {
+        let (sender, receiver) = channel::<HashJob>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            // The threads stop when the pool is dropped.
+            thread::spawn(move || loop {
+                let job = match receiver.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => return,
+                };
+                let digest = digest_with(job.algorithm, |hasher| {
+                    job.chunks
+                        .iter()
+                        .try_for_each(|chunk| hasher.write_all(&chunk))
+                });
+                // The reader is gone when reading the file failed.
+                let _ = job.digest.send(digest);
+            });
+        }
+        Hashers {
+            jobs: Mutex::new(sender),
+        }
+    }
+
+    /// Reads the files on the current thread, while one of the hasher threads hashes them.
+    fn hash_file(
+        &self,
+        progress: &dyn Progress,
+        paths: &[PathBuf],
+        algorithm: HashAlgorithm,
+        buffer_size: usize,
+    ) -> io::Result<String> {
+        let stopped = || io::Error::new(io::ErrorKind::Other, "the hasher threads stopped");
+        let (chunks, receiver) = sync_channel(CHUNKS_IN_FLIGHT);
+        let (sender, digest) = channel();
+        self.jobs
+            .lock()
+            .unwrap()
+            .send(HashJob {
+                algorithm,
+                chunks: receiver,
+                digest: sender,
+            })
+            .map_err(|_| stopped())?;
+        read_chunks(progress, paths, buffer_size, |chunk| {
+            chunks.send(chunk.to_vec()).map_err(|_| stopped())
+        })?;
+        // Tells the hasher that the whole file was read.
+        drop(chunks);
+        digest.recv().map_err(|_| stopped())?
+    }
+}
+
+fn get_unpacked_size(archive: &mut ZipArchive<File>) -> ZipResult<u64> {
+    (0..archive.len()).try_fold(0, |total, i| Ok(total + archive.by_index_raw(i)?.size()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::NoProgress;
+    use std::fs::{read_dir, write};
+
+    fn zip_file(filename: &str, packedsize: u64) -> ZipFile<'static> {
+        ZipFile {
+            country_id: 1,
+            filename: filename.to_string(),
+            packedsize,
+            unpackedsize: packedsize,
+            md5: Some("d41d8cd98f00b204e9800998ecf8427e"),
+            sha1: None,
+            sha256: None,
+            optional: false,
+        }
+    }
+
+    fn entry(dir: &Path, filename: &str) -> DirEntry {
+        read_dir(dir)
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|e| e.file_name() == filename)
+            .unwrap()
+    }
+
+    /// Permissions do not stop root from reading a file, a dangling symlink cannot be opened by anyone.
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_file_is_reported_with_its_name() {
+        let dir = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(dir.path().join("gone"), dir.path().join("1_01.zip")).unwrap();
+        let processor = Processor {
+            ignore_size: true,
+            ..Processor::default()
+        };
+        let problem = processor
+            .process_file(
+                &NoProgress,
+                &entry(dir.path(), "1_01.zip"),
+                zip_file("1_01.zip", 10),
+            )
+            .unwrap_err();
+        assert!(
+            matches!(&problem, Problem::IoError { filename, .. } if filename == "1_01.zip"),
+            "{problem:?}"
+        );
+        assert!(problem.to_string().contains("1_01.zip"));
+    }
+
+    #[test]
+    fn empty_file_is_reported_as_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        File::create(dir.path().join("1_01.zip")).unwrap();
+        let problem = Processor::default()
+            .process_file(
+                &NoProgress,
+                &entry(dir.path(), "1_01.zip"),
+                zip_file("1_01.zip", 10),
+            )
+            .unwrap_err();
+        assert!(
+            matches!(&problem, Problem::Empty { filename, expected: 10 } if filename == "1_01.zip"),
+            "{problem:?}"
+        );
+    }
+
+    #[test]
+    fn parts_are_hashed_as_one_file() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path().join("1_01.zip.001"), b"PK\x03\x04first").unwrap();
+        write(dir.path().join("1_01.zip.002"), b"second").unwrap();
+        let digest = digest_bytes(HashAlgorithm::Md5, b"PK\x03\x04firstsecond");
+        let parts = [
+            (1, entry(dir.path(), "1_01.zip.001")),
+            (2, entry(dir.path(), "1_01.zip.002")),
+        ];
+        let verified = Processor::default()
+            .process_parts(
+                &NoProgress,
+                &parts,
+                ZipFile {
+                    md5: Some(&digest),
+                    ..zip_file("1_01.zip", 15)
+                },
+            )
+            .unwrap();
+        assert_eq!(verified.checksum, Some((HashAlgorithm::Md5, digest)));
+    }
+
+    #[test]
+    fn first_missing_part_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["1_01.zip.001", "1_01.zip.003", "1_01.zip.004"] {
+            File::create(dir.path().join(name)).unwrap();
+        }
+        let parts = [
+            (1, entry(dir.path(), "1_01.zip.001")),
+            (3, entry(dir.path(), "1_01.zip.003")),
+            (4, entry(dir.path(), "1_01.zip.004")),
+        ];
+        let problem = Processor::default()
+            .process_parts(&NoProgress, &parts, zip_file("1_01.zip", 10))
+            .unwrap_err();
+        assert!(
+            matches!(&problem, Problem::MissingPart { filename, part: 2 } if filename == "1_01.zip"),
+            "{problem:?}"
+        );
+    }
 }
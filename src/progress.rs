@@ -0,0 +1,230 @@
+use indicatif::ProgressBar;
+use std::{
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Receives the amount of work done (usually the number of bytes processed), from multiple threads.
+pub trait Progress: Sync {
+    fn inc(&self, bytes: u64);
+
+    /// Called when a file has been checked, whatever the outcome.
+    fn file_done(&self) {}
+
+    /// Called before the contents of a file are hashed.
+    fn hashing(&self, _filename: &str) {}
+
+    /// Called when a file has been hashed, or hashing failed.
+    fn hashed(&self, _filename: &str) {}
+
+    /// Prints a line without garbling the progress display.
+    fn println(&self, line: &str) {
+        println!("{line}");
+    }
+}
+
+impl Progress for ProgressBar {
+    fn inc(&self, bytes: u64) {
+        ProgressBar::inc(self, bytes)
+    }
+
+    fn println(&self, line: &str) {
+        // A hidden bar (e.g. when not attached to a terminal) drops printed lines.
+        if self.is_hidden() {
+            println!("{line}");
+        } else {
+            ProgressBar::println(self, line)
+        }
+    }
+}
+
+/// Shows the number of checked files in the message of a byte progress bar, so it visibly moves even when checking
+/// many small files, followed by the file that was most recently started to be hashed.
+pub struct FileCountProgress {
+    bar: ProgressBar,
+    done: AtomicU64,
+    total: u64,
+    hashing: Mutex<Vec<String>>,
+}
+
+impl FileCountProgress {
+    pub fn new(bar: ProgressBar, total: u64) -> Self {
+        let progress = FileCountProgress {
+            bar,
+            done: AtomicU64::new(0),
+            total,
+            hashing: Mutex::new(Vec::new()),
+        };
+        progress.update_message();
+        progress
+    }
+
+    pub fn bar(&self) -> &ProgressBar {
+        &self.bar
+    }
+
+    fn update_message(&self) {
+        let done = self.done.load(Ordering::Relaxed);
+        let mut message = format!("{done}/{} files", self.total);
+        if let Some(filename) = self.hashing.lock().unwrap().last() {
+            message.push_str(&format!(", hashing {filename}"));
+        }
+        self.bar.set_message(message);
+    }
+}
+
+impl Progress for FileCountProgress {
+    fn inc(&self, bytes: u64) {
+        self.bar.inc(bytes)
+    }
+
+    fn file_done(&self) {
+        self.done.fetch_add(1, Ordering::Relaxed);
+        self.update_message();
+    }
+
+    fn hashing(&self, filename: &str) {
+        self.hashing.lock().unwrap().push(filename.to_string());
+        self.update_message();
+    }
+
+    fn hashed(&self, filename: &str) {
+        self.hashing.lock().unwrap().retain(|f| f != filename);
+        self.update_message();
+    }
+
+    fn println(&self, line: &str) {
+        Progress::println(&self.bar, line)
+    }
+}
+
+/// Prints `PROGRESS <bytes done> <bytes total>` lines to stderr at most every `interval`, for a parent process that
+/// shows its own progress bar.
+pub struct MachineProgress {
+    done: AtomicU64,
+    total: u64,
+    interval: Duration,
+    last_print: Mutex<Instant>,
+}
+
+impl MachineProgress {
+    pub fn new(total: u64, interval: Duration) -> Self {
+        let progress = MachineProgress {
+            done: AtomicU64::new(0),
+            total,
+            interval,
+            last_print: Mutex::new(Instant::now()),
+        };
+        progress.print(0);
+        progress
+    }
+
+    /// Prints the final progress, which is not throttled.
+    pub fn finish(&self) {
+        self.print(self.done.load(Ordering::Relaxed));
+    }
+
+    fn print(&self, done: u64) {
+        eprintln!("PROGRESS {done} {}", self.total);
+    }
+}
+
+impl Progress for MachineProgress {
+    fn inc(&self, bytes: u64) {
+        let done = self.done.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        let mut last_print = self.last_print.lock().unwrap();
+        if last_print.elapsed() >= self.interval {
+            *last_print = Instant::now();
+            self.print(done);
+        }
+    }
+}
+
+/// Ignores all progress, for headless use.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn inc(&self, _bytes: u64) {}
+}
+
+/// Forwards progress, but only the bytes beyond those already reported by an earlier attempt, so retrying a read
+/// does not count the same bytes twice.
+pub struct RetryProgress<'a> {
+    inner: &'a dyn Progress,
+    position: AtomicU64,
+    reported: AtomicU64,
+}
+
+impl<'a> RetryProgress<'a> {
+    pub fn new(inner: &'a dyn Progress) -> Self {
+        RetryProgress {
+            inner,
+            position: AtomicU64::new(0),
+            reported: AtomicU64::new(0),
+        }
+    }
+
+    /// Starts reading from the beginning again.
+    pub fn restart(&self) {
+        self.position.store(0, Ordering::Relaxed);
+    }
+
+    /// Reports the remaining bytes up to `position` without reading them, e.g. when giving up on a file.
+    pub fn skip_to(&self, position: u64) {
+        let reported = self.reported.fetch_max(position, Ordering::Relaxed);
+        if position > reported {
+            self.inner.inc(position - reported);
+        }
+    }
+}
+
+impl Progress for RetryProgress<'_> {
+    fn inc(&self, bytes: u64) {
+        let position = self.position.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        let reported = self.reported.fetch_max(position, Ordering::Relaxed);
+        if position > reported {
+            self.inner.inc(position - reported);
+        }
+    }
+
+    fn file_done(&self) {
+        self.inner.file_done()
+    }
+
+    fn hashing(&self, filename: &str) {
+        self.inner.hashing(filename)
+    }
+
+    fn hashed(&self, filename: &str) {
+        self.inner.hashed(filename)
+    }
+
+    fn println(&self, line: &str) {
+        self.inner.println(line)
+    }
+}
+
+/// Reports every chunk of bytes that is read to a [`Progress`].
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a dyn Progress,
+}
+
+impl<'a, R> ProgressReader<'a, R> {
+    pub fn new(inner: R, progress: &'a dyn Progress) -> Self {
+        ProgressReader { inner, progress }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.inc(n as u64);
+        Ok(n)
+    }
+}
@@ -0,0 +1,44 @@
+use crate::problem::{Problem, ProblemList};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+/// Machine-readable overview of a check, written by `--report`.
+#[derive(Debug, Serialize)]
+pub struct Report<'a> {
+    pub region: &'a str,
+    pub files: usize,
+    pub missing: usize,
+    pub corrupt: usize,
+    pub truncated: usize,
+    pub errors: usize,
+    pub problems: &'a [Problem],
+}
+
+impl<'a> Report<'a> {
+    pub fn new(region: &'a str, files: usize, problems: &'a [Problem]) -> Self {
+        let missing = problems.missing_files().len();
+        let corrupt = problems.corrupt_files().len();
+        let truncated = problems.truncated_files().len();
+        Report {
+            region,
+            files,
+            missing,
+            corrupt,
+            truncated,
+            errors: problems.len() - missing - corrupt - truncated,
+            problems,
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let file = File::create(path).context("Could not create report file")?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, self).context("Could not write report")?;
+        writer.flush().context("Could not write report")
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{
+    manifest::{Country, ZipFile},
+    problem::Problem,
+};
+use std::{cmp::Ordering, collections::HashSet, fmt};
+
+/// How complete the download of a single country is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CountryStatus {
+    pub name: String,
+    /// The number of checked files of this country.
+    pub total: usize,
+    /// The number of files without problems.
+    pub ok: usize,
+    /// The number of problems with the files of this country.
+    pub problems: usize,
+}
+
+impl CountryStatus {
+    /// The fraction of files without problems, between 0 and 1.
+    pub fn completeness(&self) -> f64 {
+        if self.total == 0 {
+            1.0
+        } else {
+            self.ok as f64 / self.total as f64
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.ok == self.total
+    }
+}
+
+impl fmt::Display for CountryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}/{} files OK ({:.0}%)",
+            self.name,
+            self.ok,
+            self.total,
+            self.completeness() * 100.0
+        )
+    }
+}
+
+/// Pairs the checked `files` of every country with the problems found, in the order of `countries`.
+pub fn country_statuses(
+    countries: &[&Country],
+    files: &[ZipFile],
+    problems: &[Problem],
+) -> Vec<CountryStatus> {
+    let broken: HashSet<_> = problems.iter().filter_map(|p| p.filename()).collect();
+    countries
+        .iter()
+        .map(|country| {
+            let filenames: Vec<_> = files
+                .iter()
+                .filter(|f| f.country_id == country.id)
+                .map(|f| f.filename.as_str())
+                .collect();
+            let problems = problems
+                .iter()
+                .filter(|p| p.filename().map_or(false, |f| filenames.contains(&f)))
+                .count();
+            let total = filenames.len();
+            let failed = filenames.iter().filter(|f| broken.contains(&f[..])).count();
+            CountryStatus {
+                name: country.name.clone(),
+                total,
+                ok: total - failed,
+                problems,
+            }
+        })
+        .collect()
+}
+
+/// Returns the countries that are not complete, least complete first.
+pub fn incomplete(statuses: &[CountryStatus]) -> Vec<&CountryStatus> {
+    let mut incomplete: Vec<_> = statuses.iter().filter(|s| !s.is_complete()).collect();
+    incomplete.sort_by(|a, b| {
+        a.completeness()
+            .partial_cmp(&b.completeness())
+            .unwrap_or(Ordering::Equal)
+    });
+    incomplete
+}
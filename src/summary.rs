@@ -0,0 +1,109 @@
+use crate::problem::{Problem, ProblemList};
+use console::Style;
+use indicatif::HumanBytes;
+use std::{collections::BTreeMap, fmt};
+
+/// Overview of a complete check, printed at the end of every run.
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub region: String,
+    pub countries: usize,
+    pub files: usize,
+    pub total_size: u64,
+    /// The combined size of the expected files that exist on disk.
+    pub on_disk: u64,
+    /// The number of checked files without problems.
+    pub ok: usize,
+    /// The number of problems by kind (see [`Problem::kind`]).
+    pub problems: BTreeMap<&'static str, usize>,
+    /// The number of missing files.
+    pub missing: usize,
+    /// The number of partially downloaded files.
+    pub incomplete: usize,
+    /// The number of corrupt files.
+    pub corrupt: usize,
+}
+
+impl Summary {
+    pub fn new(
+        region: &str,
+        countries: usize,
+        files: usize,
+        total_size: u64,
+        on_disk: u64,
+        ok: usize,
+        problems: &[Problem],
+    ) -> Self {
+        let mut counts = BTreeMap::new();
+        for problem in problems {
+            *counts.entry(problem.kind()).or_default() += 1;
+        }
+        Summary {
+            region: region.to_string(),
+            countries,
+            files,
+            total_size,
+            on_disk,
+            ok,
+            problems: counts,
+            missing: problems.missing_files().len(),
+            incomplete: problems.truncated_files().len(),
+            corrupt: problems.corrupt_files().len(),
+        }
+    }
+
+    /// Prints a single line with the number of files by status, in color unless disabled with `--color`.
+    pub fn print_colored(&self) {
+        let green = Style::new().green().bold();
+        let total: usize = self.problems.values().sum();
+        if total == 0 {
+            let line = format!(
+                "OK: {}, no problems encountered, you are good to go!",
+                self.ok
+            );
+            println!("{}", green.apply_to(line));
+            return;
+        }
+        let yellow = Style::new().yellow().bold();
+        let red = Style::new().red().bold();
+        let other = total - self.missing - self.incomplete - self.corrupt;
+        let mut line = format!("{}", green.apply_to(format!("OK: {}", self.ok)));
+        for (label, count, style) in [
+            ("Missing", self.missing, &yellow),
+            ("Incomplete", self.incomplete, &yellow),
+            ("Corrupt", self.corrupt, &red),
+            ("Other", other, &red),
+        ] {
+            if count > 0 {
+                line.push_str(&format!(
+                    "  {}",
+                    style.apply_to(format!("{label}: {count}"))
+                ));
+            }
+        }
+        println!("{line}");
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Region:    {}", self.region)?;
+        writeln!(f, "Countries: {}", self.countries)?;
+        writeln!(
+            f,
+            "Files:     {} ({})",
+            self.files,
+            HumanBytes(self.total_size)
+        )?;
+        writeln!(f, "On disk:   {}", HumanBytes(self.on_disk))?;
+        write!(f, "OK:        {}", self.ok)?;
+        if self.problems.is_empty() {
+            return write!(f, "\nProblems:  none");
+        }
+        write!(f, "\nProblems:  {}", self.problems.values().sum::<usize>())?;
+        for (kind, count) in &self.problems {
+            write!(f, "\n- {}: {count}", kind.replace('_', " "))?;
+        }
+        Ok(())
+    }
+}